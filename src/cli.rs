@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::time::Duration;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use uuid::Uuid;
+use crate::config::{Config, DestinationConfig, HistoryConfig};
+use crate::discovery::{discover as discover_printers, locate};
+use crate::helpers::{download_pages, job_settings, Color, Content, Source};
+use crate::history::{to_csv, to_table, History, HistoryFilter};
+use crate::hp_api::HpApi;
+use crate::logging::LogFormat;
+use crate::objects::{EventTable, InputSourceCaps, WalkupDestination};
+use crate::processing::process;
+
+/// Scan dimensions are reported in 1/300 inch.
+const MM_PER_UNIT: f64 = 25.4 / 300.0;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Scan to mail and other destinations from HP printers")]
+pub struct Cli {
+	#[command(subcommand)]
+	pub command: Option<Command>,
+	/// Log as text or one JSON object per line, filtered by RUST_LOG
+	#[arg(long, global = true, value_enum, env = "LOG_FORMAT", default_value_t = LogFormat::Text)]
+	pub log_format: LogFormat,
+	/// Name of the printer to use, the first configured printer by default
+	#[arg(long, global = true)]
+	pub printer: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+	/// Register the destinations on the printers and wait for scans (default)
+	Run,
+	/// Show or export the scan history
+	History(HistoryArgs),
+	/// Show the scanner and document feeder state
+	Status,
+	/// Show the color modes, sizes and resolutions the scanner supports
+	Caps,
+	/// Manage the walk-up destinations registered on the printer
+	Destinations {
+		#[command(subcommand)]
+		command: DestinationsCommand,
+	},
+	/// Print the events of the printer
+	Events(EventsArgs),
+	/// Scan once and write the document to a file
+	Scan(ScanArgs),
+	/// Search the network for HP printers via mDNS
+	Discover(DiscoverArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DestinationsCommand {
+	/// List all destinations, including those of other computers
+	List,
+	/// Register a destination, it stays until deleted
+	Add {
+		name: String,
+	},
+	/// Delete a destination by its UUID
+	Delete {
+		uuid: Uuid,
+	},
+	/// Delete the destinations named like a destination configured for the
+	/// printer, left over by earlier runs
+	Purge {
+		/// Delete every destination, including those of other computers
+		#[arg(long)]
+		all: bool,
+	},
+}
+
+#[derive(Args, Debug)]
+pub struct EventsArgs {
+	/// Keep waiting for new events
+	#[arg(long, short)]
+	follow: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct DiscoverArgs {
+	/// Seconds to wait for answers
+	#[arg(long, default_value_t = 5)]
+	timeout: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct ScanArgs {
+	/// PDF file to write
+	#[arg(long, short)]
+	out: String,
+	#[arg(long, default_value_t = 200, value_parser = clap::value_parser!(u16).range(75..=1200))]
+	dpi: u16,
+	#[arg(long, value_enum, default_value_t = Source::Auto)]
+	source: Source,
+	#[arg(long, value_enum, default_value_t = Color::Color)]
+	color: Color,
+	#[arg(long, value_enum, default_value_t = Content::Document)]
+	content: Content,
+	/// Process like scans to this destination, without delivering to its sinks
+	#[arg(long)]
+	destination: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct HistoryArgs {
+	/// SQLite database to read instead of the configured one
+	#[arg(long)]
+	database: Option<String>,
+	/// Only scans to this destination
+	#[arg(long)]
+	destination: Option<String>,
+	/// Only scans started at or after this date (YYYY-MM-DD) or time (RFC 3339)
+	#[arg(long, value_parser = parse_since)]
+	since: Option<DateTime<Local>>,
+	/// Only scans with this status: running, completed or failed
+	#[arg(long)]
+	status: Option<String>,
+	/// Maximum number of scans, newest first
+	#[arg(long)]
+	limit: Option<usize>,
+	#[arg(long, value_enum, default_value_t = Format::Table)]
+	format: Format,
+	/// Write to this file instead of stdout
+	#[arg(long, short)]
+	output: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+	Table,
+	Csv,
+	Json,
+}
+
+fn parse_since(value: &str) -> Result<DateTime<Local>, String> {
+	if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+		return Ok(time.with_timezone(&Local));
+	}
+	NaiveDate::parse_from_str(value, "%Y-%m-%d")
+		.ok()
+		.and_then(|date| date.and_hms_opt(0, 0, 0))
+		.and_then(|time| Local.from_local_datetime(&time).earliest())
+		.ok_or_else(|| format!("{} is neither a date nor an RFC 3339 time", value))
+}
+
+pub fn history(args: &HistoryArgs) -> Result<(), Box<dyn Error>> {
+	let config = match &args.database {
+		// nothing is removed or archived while reading
+		Some(database) => HistoryConfig { database: database.clone(), retention_days: 0, archive: None },
+		None => Config::load()?.history,
+	};
+	let history = History::open(&config)?;
+	let filter = HistoryFilter {
+		destination: args.destination.clone(),
+		since: args.since,
+		status: args.status.clone(),
+		limit: args.limit,
+	};
+	let scans = history.scans(&filter)?;
+
+	let output = match args.format {
+		Format::Table => to_table(&scans),
+		Format::Csv => to_csv(&scans),
+		Format::Json => serde_json::to_string_pretty(&scans)? + "\n",
+	};
+
+	match &args.output {
+		Some(path) => fs::write(path, output)?,
+		None => print!("{}", output),
+	}
+	Ok(())
+}
+
+fn connect(config: &Config, printer: Option<&str>) -> Result<HpApi, Box<dyn Error>> {
+	let printer = config.printer(printer)?;
+	locate(printer, printer.base_url()?.as_ref())
+		.ok_or_else(|| format!("Printer {} is not reachable", printer.name).into())
+}
+
+pub fn status(config: &Config, printer: Option<&str>) -> Result<(), Box<dyn Error>> {
+	let api = connect(config, printer)?;
+	let printer = api.printer_info();
+	let status = api.get_scanner_status().map_err(|e| e.to_string())?;
+
+	println!("Printer:         {} {}", printer.host, printer.model);
+	println!("Serial number:   {}", printer.serial);
+	println!("Scanner:         {}", status.scanner_status);
+	println!("Document feeder: {}", status.adf_state);
+	Ok(())
+}
+
+pub fn caps(config: &Config, printer: Option<&str>) -> Result<(), Box<dyn Error>> {
+	let api = connect(config, printer)?;
+	let caps = api.get_scan_caps().map_err(|e| e.to_string())?;
+
+	let colors = caps.color_entries.entries.iter()
+		.map(|entry| entry.color_type.as_str())
+		.collect::<Vec<&str>>();
+	println!("Color modes:     {}", colors.join(", "));
+	match &caps.platen {
+		Some(platen) => print_source("Flatbed", &platen.input_source_caps),
+		None => println!("Flatbed:         none"),
+	}
+	match &caps.adf {
+		Some(adf) => {
+			print_source("Document feeder", &adf.input_source_caps);
+			if let Some(capacity) = adf.feeder_capacity {
+				println!("  Capacity:      {} sheets", capacity);
+			}
+			if !adf.options.options.is_empty() {
+				println!("  Options:       {}", adf.options.options.join(", "));
+			}
+		}
+		None => println!("Document feeder: none"),
+	}
+	Ok(())
+}
+
+fn print_source(name: &str, caps: &InputSourceCaps) {
+	let resolutions = caps.resolutions.resolutions.iter()
+		.map(|resolution| match resolution.x_resolution == resolution.y_resolution {
+			true => resolution.x_resolution.to_string(),
+			false => format!("{}x{}", resolution.x_resolution, resolution.y_resolution),
+		})
+		.collect::<Vec<String>>();
+	println!("{}:", name);
+	println!("  Size:          {:.0} x {:.0} mm to {:.0} x {:.0} mm",
+		caps.min_width as f64 * MM_PER_UNIT, caps.min_height as f64 * MM_PER_UNIT,
+		caps.max_width as f64 * MM_PER_UNIT, caps.max_height as f64 * MM_PER_UNIT);
+	println!("  Resolutions:   {} dpi", resolutions.join(", "));
+}
+
+pub fn destinations(config: &Config, printer: Option<&str>, command: &DestinationsCommand) -> Result<(), Box<dyn Error>> {
+	let mut api = connect(config, printer)?;
+
+	match command {
+		DestinationsCommand::List => {
+			let destinations = api.get_walkup_destinations().map_err(|e| e.to_string())?;
+			println!("{:<36}  {:<24} HOSTNAME", "UUID", "NAME");
+			for destination in &destinations.destinations {
+				println!("{:<36}  {:<24} {}", destination_uuid(destination), destination.name, destination.hostname);
+			}
+		}
+		DestinationsCommand::Add { name } => {
+			let uuid = api.add_destination(WalkupDestination {
+				hostname: name.clone(),
+				name: name.clone(),
+				link_type: "Network".to_string(),
+				resource_uri: None,
+				settings: None,
+			}).map_err(|e| e.to_string())?;
+			println!("{}", uuid);
+		}
+		DestinationsCommand::Delete { uuid } => {
+			api.delete_destination(*uuid).map_err(|e| e.to_string())?;
+		}
+		DestinationsCommand::Purge { all } => {
+			let configured = config.printer(printer)?.destinations(&config.destinations);
+			let destinations = api.get_walkup_destinations().map_err(|e| e.to_string())?;
+			let mut deleted = 0;
+			for destination in &destinations.destinations {
+				if !all && !configured.iter().any(|configured| configured.name == destination.name) {
+					continue
+				}
+				let Ok(uuid) = Uuid::parse_str(&destination_uuid(destination)) else {
+					log::warn!("Destination {} has no UUID", destination.name);
+					continue
+				};
+				match api.delete_destination(uuid) {
+					Ok(()) => deleted += 1,
+					Err(e) => log::error!("Could not delete {} ({}): {}", destination.name, uuid, e),
+				}
+			}
+			println!("Deleted {} of {} destinations", deleted, destinations.destinations.len());
+		}
+	}
+	Ok(())
+}
+
+/// The UUID is the last segment of the resource URI.
+fn destination_uuid(destination: &WalkupDestination) -> String {
+	destination.resource_uri.as_deref()
+		.and_then(|uri| uri.rsplit('/').next())
+		.unwrap_or_default()
+		.to_string()
+}
+
+pub fn events(config: &Config, printer: Option<&str>, args: &EventsArgs) -> Result<(), Box<dyn Error>> {
+	let mut api = connect(config, printer)?;
+	let mut seen = HashSet::new();
+
+	let table = api.get_eventtable().map_err(|e| e.to_string())?;
+	print_events(&table, &mut seen);
+
+	if !args.follow {
+		return Ok(())
+	}
+	loop {
+		// answers without changes are reported as errors
+		if let Ok(table) = api.get_eventtable_timeout(1200) {
+			print_events(&table, &mut seen);
+		}
+	}
+}
+
+/// The printer answers with the whole table, events already printed are
+/// skipped.
+fn print_events(table: &EventTable, seen: &mut HashSet<(String, String)>) {
+	for event in &table.events {
+		if !seen.insert((event.aging_stamp.clone(), event.unqualified_event_category.clone())) {
+			continue
+		}
+		println!("{:<10} {}", event.aging_stamp, event.unqualified_event_category);
+		for payload in &event.payloads {
+			println!("{:<10}   {} {}", "", payload.resource_type, payload.resource_uri);
+		}
+	}
+}
+
+/// The serial number is read from each printer, configure it as `serial` or
+/// the name as `mdns_name` to find a printer after its address changed.
+pub fn discover(args: &DiscoverArgs) -> Result<(), Box<dyn Error>> {
+	let printers = discover_printers(Duration::from_secs(args.timeout))?;
+	if printers.is_empty() {
+		return Err("No HP printer found".into());
+	}
+
+	println!("{:<40}  {:<28} {:<14} {:<28} SERVICES", "NAME", "MODEL", "SERIAL", "URL");
+	for printer in &printers {
+		println!("{:<40}  {:<28} {:<14} {:<28} {}", printer.name, printer.model, printer.serial().unwrap_or_default(),
+			printer.url.as_str(), printer.services.join(", "));
+	}
+	Ok(())
+}
+
+pub fn scan(config: &Config, printer: Option<&str>, args: &ScanArgs) -> Result<(), Box<dyn Error>> {
+	let destination = match &args.destination {
+		Some(name) => config.destinations.iter()
+			.find(|destination| destination.name == *name)
+			.cloned()
+			.ok_or_else(|| format!("Unknown destination {}", name))?,
+		None => DestinationConfig {
+			name: "cli".to_string(),
+			..DestinationConfig::default()
+		},
+	};
+
+	let api = connect(config, printer)?;
+	let printer = api.printer_info();
+	let source = args.source.input_source(&api).map_err(|e| e.to_string())?;
+	let job = job_settings(source, args.content.content_type(), args.dpi as i16, args.color.color_space());
+	let job_location = api.create_job(job).map_err(|e| e.to_string())?;
+	let pages = download_pages(&api, &job_location, &destination.name, false).map_err(|e| e.to_string())?;
+
+	let documents = process(pages, &destination, args.dpi, &printer).map_err(|e| e.to_string())?;
+	match documents.as_slice() {
+		[] => return Err("Processing produced no document".into()),
+		[document] => {
+			fs::write(&args.out, &document.content)?;
+			log::info!("Wrote {} pages to {}", document.page_count, args.out);
+		}
+		// splitting or compression produced several documents
+		documents => {
+			let stem = args.out.strip_suffix(".pdf").unwrap_or(&args.out);
+			for (i, document) in documents.iter().enumerate() {
+				let path = format!("{}-{}.pdf", stem, i + 1);
+				fs::write(&path, &document.content)?;
+				log::info!("Wrote {} pages to {}", document.page_count, path);
+			}
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,559 @@
+use std::{env, fmt, fs};
+use reqwest::Url;
+use serde::Deserialize;
+use crate::secret::Secret;
+use crate::sinks::SinkConfig;
+use crate::sinks::sendgrid::SendgridConfig;
+use crate::users::UsersConfig;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+	/// Shorthand for a single printer
+	#[serde(default)]
+	pub printer_url: Option<String>,
+	#[serde(rename = "printer", default)]
+	pub printers: Vec<PrinterConfig>,
+	#[serde(rename = "destination", default)]
+	pub destinations: Vec<DestinationConfig>,
+	#[serde(default)]
+	pub outbox: OutboxConfig,
+	#[serde(default)]
+	pub users: UsersConfig,
+	#[serde(default)]
+	pub http: HttpConfig,
+	#[serde(default)]
+	pub history: HistoryConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PrinterConfig {
+	/// Label in logs, metrics and the web interface, the host of the URL by default
+	#[serde(default)]
+	pub name: String,
+	#[serde(default)]
+	pub url: Option<String>,
+	/// Part of the mDNS instance name the printer is searched by, used when
+	/// it is not reachable at the URL
+	#[serde(default)]
+	pub mdns_name: Option<String>,
+	/// Serial number the printer is searched by
+	#[serde(default)]
+	pub serial: Option<String>,
+	/// Destinations registered on this printer, all destinations if unset
+	#[serde(default)]
+	pub destinations: Option<Vec<String>>,
+}
+
+impl PrinterConfig {
+	pub fn base_url(&self) -> Result<Option<Url>, ConfigError> {
+		self.url.as_ref()
+			.map(|url| Url::parse(url)
+				.map_err(|e| ConfigError::new(&format!("Invalid URL {} of printer {}: {}", url, self.name, e))))
+			.transpose()
+	}
+
+	/// Whether the printer can be searched on the network.
+	pub fn discoverable(&self) -> bool {
+		self.mdns_name.is_some() || self.serial.is_some()
+	}
+
+	/// The configured destinations to register on this printer.
+	pub fn destinations(&self, destinations: &[DestinationConfig]) -> Vec<DestinationConfig> {
+		destinations.iter()
+			.filter(|destination| match &self.destinations {
+				Some(names) => names.contains(&destination.name),
+				None => true,
+			})
+			.cloned()
+			.collect()
+	}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HistoryConfig {
+	/// SQLite database logging scan jobs and deliveries
+	#[serde(default = "default_history_database")]
+	pub database: String,
+	/// Scans older than this are removed, kept forever with 0
+	#[serde(default = "default_retention_days")]
+	pub retention_days: u32,
+	/// Keeps delivered documents for download and re-sending in the web interface
+	#[serde(default = "default_archive")]
+	pub archive: Option<String>,
+}
+
+impl Default for HistoryConfig {
+	fn default() -> Self {
+		HistoryConfig {
+			database: default_history_database(),
+			retention_days: default_retention_days(),
+			archive: default_archive(),
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HttpConfig {
+	/// Address of the HTTP server serving the web interface, metrics and health checks, disabled if unset
+	#[serde(default = "default_http_bind")]
+	pub bind: Option<String>,
+	/// `/healthz` fails if the loop of a printer did not run for this long
+	#[serde(default = "default_heartbeat_timeout")]
+	pub heartbeat_timeout_secs: u64,
+	/// Basic auth for the web interface, metrics and health checks stay open
+	#[serde(default = "default_http_username")]
+	pub username: Option<String>,
+	#[serde(default = "default_http_password")]
+	pub password: Option<Secret>,
+}
+
+impl Default for HttpConfig {
+	fn default() -> Self {
+		HttpConfig {
+			bind: default_http_bind(),
+			heartbeat_timeout_secs: default_heartbeat_timeout(),
+			username: default_http_username(),
+			password: default_http_password(),
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutboxConfig {
+	/// Documents waiting for delivery are kept in `pending`, documents that
+	/// failed too often are moved to `dead-letter`
+	#[serde(default = "default_outbox_directory")]
+	pub directory: String,
+	/// Failed deliveries to a sink before the document is given up
+	#[serde(default = "default_max_attempts")]
+	pub max_attempts: u32,
+	/// Delay before the first retry, doubled for every further retry
+	#[serde(default = "default_retry_delay")]
+	pub retry_delay_secs: u64,
+	#[serde(default = "default_max_retry_delay")]
+	pub max_retry_delay_secs: u64,
+}
+
+impl Default for OutboxConfig {
+	fn default() -> Self {
+		OutboxConfig {
+			directory: default_outbox_directory(),
+			max_attempts: default_max_attempts(),
+			retry_delay_secs: default_retry_delay(),
+			max_retry_delay_secs: default_max_retry_delay(),
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct DestinationConfig {
+	pub name: String,
+	/// Template for the file name of delivered documents
+	#[serde(default)]
+	pub filename: Option<String>,
+	#[serde(default)]
+	pub deskew: Option<DeskewConfig>,
+	#[serde(default)]
+	pub split: Option<SplitConfig>,
+	#[serde(default)]
+	pub ocr: Option<OcrConfig>,
+	#[serde(default)]
+	pub pdf: Option<PdfConfig>,
+	#[serde(default)]
+	pub compression: Option<CompressionConfig>,
+	/// Where documents are delivered, a SendGrid mail configured through the environment by default
+	#[serde(default)]
+	pub sink: Vec<SinkConfig>,
+	/// Used for documents exceeding `compression.max_size` with `oversize = "fallback"`
+	#[serde(default)]
+	pub fallback_sink: Vec<SinkConfig>,
+}
+
+impl DestinationConfig {
+	/// Largest PDF that still fits `compression.max_size` once every sink
+	/// encoded it.
+	pub fn max_document_size(&self) -> Option<u64> {
+		let max_size = self.compression.as_ref()?.max_size?;
+		Some(self.sink.iter()
+			.map(|sink| sink.max_document_size(max_size))
+			.min()
+			.unwrap_or(max_size))
+	}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeskewConfig {
+	/// Rotate pages according to the `ImageOrientation` reported by the scanner
+	#[serde(default = "default_true")]
+	pub use_orientation_hint: bool,
+	/// Detect upside down or sideways pages with tesseract's orientation detection
+	#[serde(default)]
+	pub detect_orientation: bool,
+	/// Largest skew angle in degrees that is corrected
+	#[serde(default = "default_max_skew_angle")]
+	pub max_angle: f32,
+	#[serde(default = "default_jpeg_quality")]
+	pub jpeg_quality: u8,
+	#[serde(default = "default_tesseract")]
+	pub tesseract: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SplitConfig {
+	/// Split at blank pages
+	#[serde(default)]
+	pub blank_pages: bool,
+	/// Share of dark pixels below which a page counts as blank
+	#[serde(default = "default_blank_threshold")]
+	pub blank_threshold: f32,
+	/// Split at pages carrying a barcode or QR code
+	#[serde(default)]
+	pub barcode: bool,
+	/// Only barcodes starting with this prefix mark a separator sheet, must
+	/// not be empty so barcodes on the documents themselves are kept
+	#[serde(default = "default_barcode_prefix")]
+	pub barcode_prefix: String,
+	#[serde(default = "default_zbarimg")]
+	pub zbarimg: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OcrConfig {
+	#[serde(default = "default_ocr_languages")]
+	pub languages: Vec<String>,
+	#[serde(default = "default_ocr_timeout")]
+	pub timeout_secs: u64,
+	#[serde(default = "default_tesseract")]
+	pub tesseract: String,
+}
+
+/// Document metadata fields are templates, see README for the placeholders.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PdfConfig {
+	/// Produce PDF/A-2b documents
+	#[serde(default)]
+	pub pdfa: bool,
+	/// sRGB ICC profile embedded as output intent for PDF/A
+	#[serde(default = "default_icc_profile")]
+	pub icc_profile: String,
+	pub title: Option<String>,
+	pub author: Option<String>,
+	pub subject: Option<String>,
+	pub keywords: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CompressionConfig {
+	pub jpeg_quality: Option<u8>,
+	#[serde(default)]
+	pub grayscale: bool,
+	/// Pages scanned with a higher resolution are downsampled
+	pub max_resolution: Option<u16>,
+	/// Maximum document size in bytes as sent by the sinks, base64 encoded
+	/// for mails
+	pub max_size: Option<u64>,
+	/// Re-encode with stronger compression until the document fits `max_size`
+	#[serde(default = "default_true")]
+	pub auto: bool,
+	#[serde(default)]
+	pub oversize: Oversize,
+}
+
+/// What happens to documents that still exceed the maximum size.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Oversize {
+	/// Split into several documents that each fit
+	#[default]
+	Split,
+	/// Deliver to the fallback sinks instead
+	Fallback,
+}
+
+fn default_http_bind() -> Option<String> {
+	env::var("HTTP_BIND").ok()
+}
+
+fn default_http_username() -> Option<String> {
+	env::var("HTTP_USERNAME").ok()
+}
+
+fn default_http_password() -> Option<Secret> {
+	Secret::from_env("HTTP_PASSWORD")
+}
+
+fn default_heartbeat_timeout() -> u64 {
+	10 * 60
+}
+
+fn default_history_database() -> String {
+	env::var("HISTORY_DATABASE").unwrap_or("./history.sqlite".to_string())
+}
+
+fn default_retention_days() -> u32 {
+	365
+}
+
+fn default_archive() -> Option<String> {
+	env::var("ARCHIVE_DIR").ok()
+}
+
+fn default_outbox_directory() -> String {
+	env::var("OUTBOX_DIR").unwrap_or("./outbox".to_string())
+}
+
+fn default_max_attempts() -> u32 {
+	10
+}
+
+fn default_retry_delay() -> u64 {
+	30
+}
+
+fn default_max_retry_delay() -> u64 {
+	60 * 60
+}
+
+fn default_true() -> bool {
+	true
+}
+
+fn default_max_skew_angle() -> f32 {
+	5.0
+}
+
+fn default_jpeg_quality() -> u8 {
+	90
+}
+
+fn default_blank_threshold() -> f32 {
+	0.005
+}
+
+fn default_barcode_prefix() -> String {
+	"PATCH".to_string()
+}
+
+fn default_zbarimg() -> String {
+	"zbarimg".to_string()
+}
+
+fn default_icc_profile() -> String {
+	"/usr/share/color/icc/sRGB.icc".to_string()
+}
+
+fn default_ocr_languages() -> Vec<String> {
+	vec!["eng".to_string()]
+}
+
+fn default_ocr_timeout() -> u64 {
+	120
+}
+
+fn default_tesseract() -> String {
+	"tesseract".to_string()
+}
+
+impl Config {
+	/// Reads the configuration file referenced by `CONFIG_FILE`. Without a
+	/// file a single destination named after `SCAN_NAME` is used.
+	pub fn load() -> Result<Config, ConfigError> {
+		let mut config = match env::var("CONFIG_FILE") {
+			Ok(path) => {
+				log::info!("Reading configuration from {}", path);
+				let content = fs::read_to_string(&path)
+					.map_err(|e| ConfigError::new(&format!("Error reading {}: {}", path, e)))?;
+				toml::from_str::<Config>(&content)
+					.map_err(|e| ConfigError::new(&format!("Error parsing {}: {}", path, e)))?
+			}
+			Err(_) => Config {
+				printer_url: None,
+				printers: Vec::new(),
+				destinations: Vec::new(),
+				outbox: OutboxConfig::default(),
+				users: UsersConfig::default(),
+				http: HttpConfig::default(),
+				history: HistoryConfig::default(),
+			}
+		};
+
+		if config.printer_url.is_none() {
+			config.printer_url = env::var("PRINTER_URL").ok();
+		}
+		let printer = PrinterConfig {
+			name: String::new(),
+			url: config.printer_url.clone(),
+			mdns_name: env::var("PRINTER_MDNS_NAME").ok(),
+			serial: env::var("PRINTER_SERIAL").ok(),
+			destinations: None,
+		};
+		if config.printers.is_empty() && (printer.url.is_some() || printer.discoverable()) {
+			config.printers.push(printer);
+		}
+
+		let users = config.users.destinations(&config.destinations)?;
+		config.destinations.extend(users);
+
+		if config.destinations.is_empty() {
+			config.destinations.push(DestinationConfig {
+				name: env::var("SCAN_NAME").unwrap_or("an Email".to_string()),
+				filename: None,
+				deskew: None,
+				split: None,
+				ocr: None,
+				pdf: None,
+				compression: None,
+				sink: Vec::new(),
+				fallback_sink: Vec::new(),
+			});
+		}
+
+		config.validate()?;
+		Ok(config)
+	}
+
+	/// Checks references between printers and destinations and the settings
+	/// of the destinations, naming unnamed printers on the way.
+	fn validate(&mut self) -> Result<(), ConfigError> {
+		let config = self;
+		if config.http.username.is_some() != config.http.password.is_some() {
+			return Err(ConfigError::new("HTTP basic auth needs both a username and a password"));
+		}
+		if config.outbox.retry_delay_secs == 0 {
+			return Err(ConfigError::new("The outbox retry_delay_secs must be at least 1"));
+		}
+
+		for (i, destination) in config.destinations.iter().enumerate() {
+			if config.destinations[..i].iter().any(|other| other.name == destination.name) {
+				return Err(ConfigError::new(&format!("Destination {} is configured twice", destination.name)));
+			}
+		}
+
+		for i in 0..config.printers.len() {
+			let printer = &mut config.printers[i];
+			let url = printer.base_url()?;
+			if url.is_none() && !printer.discoverable() {
+				return Err(ConfigError::new("Printers need a url, mdns_name or serial"));
+			}
+			if printer.name.is_empty() {
+				printer.name = url.as_ref().and_then(|url| url.host_str().map(str::to_string))
+					.or(printer.mdns_name.clone())
+					.or(printer.serial.clone())
+					.unwrap_or_default();
+			}
+			let printer = &config.printers[i];
+			if config.printers[..i].iter().any(|other| other.name == printer.name) {
+				return Err(ConfigError::new(&format!("Printer {} is configured twice", printer.name)));
+			}
+			for name in printer.destinations.iter().flatten() {
+				if !config.destinations.iter().any(|destination| destination.name == *name) {
+					return Err(ConfigError::new(&format!("Printer {} uses unknown destination {}", printer.name, name)));
+				}
+			}
+		}
+
+		for destination in config.destinations.iter_mut() {
+			if destination.sink.is_empty() {
+				destination.sink.push(SinkConfig::Sendgrid(SendgridConfig::default()));
+			}
+			if destination.split.as_ref().is_some_and(|split| split.barcode && split.barcode_prefix.is_empty()) {
+				return Err(ConfigError::new(&format!("Destination {} splits at barcodes but barcode_prefix is empty", destination.name)));
+			}
+			if destination.deskew.as_ref().is_some_and(|deskew| !(deskew.max_angle > 0.0 && deskew.max_angle <= 45.0)) {
+				return Err(ConfigError::new(&format!("Destination {} needs a deskew max_angle above 0 and at most 45 degrees", destination.name)));
+			}
+			if let Some(pdf) = destination.pdf.as_ref().filter(|pdf| pdf.pdfa) {
+				fs::File::open(&pdf.icc_profile)
+					.map_err(|e| ConfigError::new(&format!("Destination {} produces PDF/A but ICC profile {} is not readable: {}", destination.name, pdf.icc_profile, e)))?;
+			}
+			for sink in destination.sink.iter().chain(destination.fallback_sink.iter()) {
+				sink.validate()
+					.map_err(|e| ConfigError::new(&format!("Sink of destination {}: {}", destination.name, e)))?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// The printer of the given name, the first printer if unset.
+	pub fn printer(&self, name: Option<&str>) -> Result<&PrinterConfig, ConfigError> {
+		match name {
+			Some(name) => self.printers.iter()
+				.find(|printer| printer.name == name)
+				.ok_or_else(|| ConfigError::new(&format!("Unknown printer {}", name))),
+			None => self.printers.first()
+				.ok_or_else(|| ConfigError::new("PRINTER_URL, PRINTER_MDNS_NAME, PRINTER_SERIAL or a [[printer]] must be set")),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+	pub details: String,
+}
+
+impl ConfigError {
+	pub fn new(msg: &str) -> ConfigError {
+		ConfigError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.details)
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn validate(toml: &str) -> Result<Config, ConfigError> {
+		let mut config = toml::from_str::<Config>(toml).unwrap();
+		config.validate()?;
+		Ok(config)
+	}
+
+	#[test]
+	fn accepts_deskew_angles() {
+		assert!(validate("[[destination]]\nname = \"Office\"\n[destination.deskew]\n").is_ok());
+		assert!(validate("[[destination]]\nname = \"Office\"\n[destination.deskew]\nmax_angle = 45.0").is_ok());
+	}
+
+	#[test]
+	fn rejects_deskew_angles_out_of_range() {
+		for angle in ["0.0", "-5.0", "45.5", "nan"] {
+			let error = validate(&format!("[[destination]]\nname = \"Office\"\n[destination.deskew]\nmax_angle = {}", angle)).unwrap_err();
+			assert!(error.details.contains("max_angle"), "{}: {}", angle, error);
+		}
+	}
+
+	#[test]
+	fn rejects_retries_without_delay() {
+		let error = validate("[outbox]\nretry_delay_secs = 0").unwrap_err();
+		assert!(error.details.contains("retry_delay_secs"), "{}", error);
+		assert!(validate("[outbox]\nretry_delay_secs = 1").is_ok());
+	}
+
+	#[test]
+	fn rejects_incomplete_http_credentials() {
+		let mut config = toml::from_str::<Config>("[http]\nusername = \"admin\"").unwrap();
+		config.http.password = None;
+		let error = config.validate().unwrap_err();
+		assert_eq!(error.details, "HTTP basic auth needs both a username and a password");
+	}
+
+	#[test]
+	fn rejects_duplicate_destinations() {
+		let error = validate("[[destination]]\nname = \"Office\"\n[[destination]]\nname = \"Office\"").unwrap_err();
+		assert_eq!(error.details, "Destination Office is configured twice");
+	}
+
+	#[test]
+	fn rejects_unknown_destinations_of_printers() {
+		let error = validate("[[printer]]\nurl = \"http://printer\"\ndestinations = [\"Home\"]\n[[destination]]\nname = \"Office\"").unwrap_err();
+		assert_eq!(error.details, "Printer printer uses unknown destination Home");
+	}
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use reqwest::Url;
+use crate::config::PrinterConfig;
+use crate::hp_api::HpApi;
+
+/// Services announced by HP printers, `_http._tcp` only counts with HP
+/// TXT records.
+const SERVICE_TYPES: [&str; 3] = ["_uscan._tcp.local.", "_pdl-datastream._tcp.local.", "_http._tcp.local."];
+
+/// How long a configured printer is searched for.
+const FIND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The only service whose port is the web server the scan API runs on.
+const WEB_SERVICE: &str = "_http._tcp";
+
+/// A printer found on the network.
+#[derive(Debug, Clone)]
+pub struct DiscoveredPrinter {
+	/// Instance name of the service, like `HP OfficeJet Pro 8720 [A1B2C3]`
+	pub name: String,
+	pub model: String,
+	pub url: Url,
+	pub services: Vec<String>,
+}
+
+impl DiscoveredPrinter {
+	/// Serial number reported by the printer, the TXT records do not carry it.
+	pub fn serial(&self) -> Option<String> {
+		let api = HpApi::with_timeout(self.url.clone(), Duration::from_secs(3));
+		match api.get_product_config() {
+			Ok(config) => Some(config.product_information.serial_number),
+			Err(e) => {
+				log::debug!("Could not read serial number of {}: {}", self.name, e);
+				None
+			}
+		}
+	}
+}
+
+/// Browses the network for HP printers until the timeout.
+pub fn discover(timeout: Duration) -> Result<Vec<DiscoveredPrinter>, DiscoveryError> {
+	browse(timeout, |_| false)
+}
+
+/// Looks up a printer by its mDNS name or serial number, returns as soon as
+/// it is found.
+pub fn find(config: &PrinterConfig) -> Result<Option<Url>, DiscoveryError> {
+	let mut found = None;
+	browse(FIND_TIMEOUT, |printer| {
+		if !matches(config, printer, DiscoveredPrinter::serial) {
+			return false
+		}
+		found = Some(printer.url.clone());
+		true
+	})?;
+	Ok(found)
+}
+
+/// Connects to the printer at the known address, or searches it by name or
+/// serial number if it does not answer there, for example after it got a
+/// new address from DHCP.
+pub fn locate(config: &PrinterConfig, known: Option<&Url>) -> Option<HpApi> {
+	if let Some(url) = known {
+		let api = HpApi::new(url.clone()).named(&config.name);
+		if api.connection_check() {
+			return Some(api)
+		}
+	}
+	if !config.discoverable() {
+		return None
+	}
+
+	match find(config) {
+		Ok(Some(url)) => {
+			log::info!("Found printer {} at {}", config.name, url);
+			let api = HpApi::new(url).named(&config.name);
+			api.connection_check().then_some(api)
+		}
+		Ok(None) => {
+			log::warn!("Printer {} not found on the network", config.name);
+			None
+		}
+		Err(e) => {
+			log::error!("Error searching printer {}: {}", config.name, e);
+			None
+		}
+	}
+}
+
+/// `serial` reads the serial number from the printer, which is only done
+/// once the name matches.
+fn matches(config: &PrinterConfig, printer: &DiscoveredPrinter, serial: impl FnOnce(&DiscoveredPrinter) -> Option<String>) -> bool {
+	if let Some(name) = &config.mdns_name {
+		if !printer.name.to_lowercase().contains(&name.to_lowercase()) {
+			return false
+		}
+	}
+	match &config.serial {
+		Some(expected) => serial(printer).is_some_and(|found| found.eq_ignore_ascii_case(expected)),
+		None => true,
+	}
+}
+
+/// Collects the printers announcing any of the service types, merged by
+/// address. `stop` is called for every new printer and ends the search
+/// early.
+fn browse(timeout: Duration, mut stop: impl FnMut(&DiscoveredPrinter) -> bool) -> Result<Vec<DiscoveredPrinter>, DiscoveryError> {
+	let daemon = ServiceDaemon::new()
+		.map_err(|e| DiscoveryError::new(&format!("Error starting mDNS: {}", e)))?;
+	let receivers = SERVICE_TYPES.iter()
+		.map(|service_type| daemon.browse(service_type))
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|e| DiscoveryError::new(&format!("Error browsing mDNS services: {}", e)))?;
+
+	let mut printers: Vec<DiscoveredPrinter> = Vec::new();
+	let deadline = Instant::now() + timeout;
+	'browse: while Instant::now() < deadline {
+		for receiver in &receivers {
+			while let Ok(event) = receiver.try_recv() {
+				let ServiceEvent::ServiceResolved(info) = event else { continue };
+				let Some(printer) = printer(&info) else { continue };
+				log::debug!("Resolved {} at {}", info.get_fullname(), printer.url);
+
+				match printers.iter_mut().find(|other| other.url.host() == printer.url.host()) {
+					Some(other) => {
+						// the port announced by the web server wins over the guess
+						if printer.services[0] == WEB_SERVICE {
+							other.url = printer.url;
+						}
+						for service in printer.services {
+							if !other.services.contains(&service) {
+								other.services.push(service);
+							}
+						}
+					}
+					None => {
+						let stopped = stop(&printer);
+						printers.push(printer);
+						if stopped { break 'browse }
+					}
+				}
+			}
+		}
+		std::thread::sleep(Duration::from_millis(100));
+	}
+
+	if let Err(e) = daemon.shutdown() {
+		log::debug!("Error stopping mDNS: {}", e);
+	}
+	Ok(printers)
+}
+
+/// The printer behind a resolved service, `None` for other devices.
+fn printer(info: &ServiceInfo) -> Option<DiscoveredPrinter> {
+	let txt = info.get_properties().iter()
+		.map(|property| (property.key().to_lowercase(), property.val_str().to_string()))
+		.collect::<HashMap<String, String>>();
+	let manufacturer = txt.get("mfg").or(txt.get("usb_mfg")).map(String::as_str).unwrap_or_default();
+	let model = txt.get("ty").cloned().unwrap_or_default();
+	if !(manufacturer.eq_ignore_ascii_case("HP") || manufacturer.eq_ignore_ascii_case("Hewlett-Packard") || model.starts_with("HP ")) {
+		return None
+	}
+
+	// IPv4 is preferred, link-local IPv6 addresses would need a zone in URLs
+	let address = info.get_addresses().iter()
+		.filter(|address| !matches!(address, IpAddr::V6(address) if address.is_unicast_link_local()))
+		.min_by_key(|address| address.is_ipv6())
+		.copied()?;
+	let service_type = info.get_type();
+	// _pdl-datastream is raw printing and _uscan is eSCL, the scan API of
+	// this crate is served by the web server on 80
+	let port = match service_type.starts_with(WEB_SERVICE) {
+		true => info.get_port(),
+		false => 80,
+	};
+	let host = match address {
+		IpAddr::V4(address) => address.to_string(),
+		IpAddr::V6(address) => format!("[{}]", address),
+	};
+	let url = Url::parse(&format!("http://{}:{}/", host, port)).ok()?;
+
+	let name = info.get_fullname()
+		.strip_suffix(service_type)
+		.unwrap_or(info.get_fullname())
+		.trim_end_matches('.')
+		.to_string();
+
+	Some(DiscoveredPrinter {
+		model: if model.is_empty() { name.clone() } else { model },
+		name,
+		url,
+		services: vec![service_type.trim_end_matches(".local.").to_string()],
+	})
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryError {
+	pub details: String,
+}
+
+impl DiscoveryError {
+	pub fn new(msg: &str) -> DiscoveryError {
+		DiscoveryError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for DiscoveryError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.details)
+	}
+}
+
+impl std::error::Error for DiscoveryError {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const NAME: &str = "HP OfficeJet Pro 8720 [A1B2C3]";
+
+	fn service(service_type: &str, addresses: &str, port: u16, txt: &[(&str, &str)]) -> ServiceInfo {
+		let properties = txt.iter()
+			.map(|(key, value)| (key.to_string(), value.to_string()))
+			.collect::<HashMap<String, String>>();
+		ServiceInfo::new(service_type, NAME, "hp.local.", addresses, port, properties).unwrap()
+	}
+
+	fn config(mdns_name: Option<&str>, serial: Option<&str>) -> PrinterConfig {
+		PrinterConfig {
+			name: "office".to_string(),
+			url: None,
+			mdns_name: mdns_name.map(str::to_string),
+			serial: serial.map(str::to_string),
+			destinations: None,
+		}
+	}
+
+	#[test]
+	fn reads_web_services() {
+		let info = service("_uscan._tcp.local.", "fe80::1,192.168.1.20", 8080,
+			&[("ty", "HP OfficeJet Pro 8720"), ("usb_MFG", "HP"), ("rs", "eSCL")]);
+		let printer = printer(&info).unwrap();
+		assert_eq!(printer.name, NAME);
+		assert_eq!(printer.model, "HP OfficeJet Pro 8720");
+		assert_eq!(printer.url.as_str(), "http://192.168.1.20/");
+		assert_eq!(printer.services, vec!["_uscan._tcp"]);
+	}
+
+	#[test]
+	fn uses_port_of_web_server() {
+		let info = service("_http._tcp.local.", "192.168.1.20", 8080, &[("ty", "HP OfficeJet Pro 8720")]);
+		assert_eq!(printer(&info).unwrap().url.as_str(), "http://192.168.1.20:8080/");
+	}
+
+	#[test]
+	fn uses_web_port_for_raw_printing() {
+		let info = service("_pdl-datastream._tcp.local.", "2001:db8::1", 9100, &[("mfg", "Hewlett-Packard")]);
+		let printer = printer(&info).unwrap();
+		assert_eq!(printer.url.as_str(), "http://[2001:db8::1]/");
+		assert_eq!(printer.model, NAME);
+	}
+
+	#[test]
+	fn skips_link_local_addresses() {
+		let info = service("_uscan._tcp.local.", "fe80::1", 8080, &[("ty", "HP OfficeJet Pro 8720")]);
+		assert!(printer(&info).is_none());
+	}
+
+	#[test]
+	fn ignores_other_devices() {
+		let nas = service("_http._tcp.local.", "192.168.1.30", 80, &[("path", "/")]);
+		assert!(printer(&nas).is_none());
+		let canon = service("_uscan._tcp.local.", "192.168.1.31", 80, &[("ty", "Canon MF640C"), ("usb_MFG", "Canon")]);
+		assert!(printer(&canon).is_none());
+	}
+
+	#[test]
+	fn matches_by_name() {
+		let info = service("_uscan._tcp.local.", "192.168.1.20", 8080, &[("ty", "HP OfficeJet Pro 8720")]);
+		let printer = printer(&info).unwrap();
+		let no_serial = |_: &DiscoveredPrinter| -> Option<String> { panic!("serial read without configured serial") };
+		assert!(matches(&config(Some("officejet pro"), None), &printer, no_serial));
+		assert!(matches(&config(Some("[a1b2c3]"), None), &printer, no_serial));
+		assert!(!matches(&config(Some("LaserJet"), None), &printer, no_serial));
+	}
+
+	#[test]
+	fn matches_by_serial() {
+		let info = service("_uscan._tcp.local.", "192.168.1.20", 8080, &[("ty", "HP OfficeJet Pro 8720")]);
+		let printer = printer(&info).unwrap();
+		assert!(matches(&config(None, Some("CN123")), &printer, |_| Some("cn123".to_string())));
+		assert!(!matches(&config(None, Some("CN123")), &printer, |_| Some("CN456".to_string())));
+		// an unreachable printer has no serial
+		assert!(!matches(&config(Some("OfficeJet"), Some("CN123")), &printer, |_| None));
+	}
+}
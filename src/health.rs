@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use chrono::Utc;
+use crate::metrics::METRICS;
+
+/// State behind the `/healthz` and `/readyz` endpoints.
+pub struct Health {
+	/// Unix timestamp of the last loop iteration by printer
+	heartbeats: Mutex<HashMap<String, i64>>,
+	/// Problems of configured sinks found at startup
+	sink_problems: Mutex<Vec<String>>,
+}
+
+pub static HEALTH: LazyLock<Health> = LazyLock::new(|| Health {
+	heartbeats: Mutex::new(HashMap::new()),
+	sink_problems: Mutex::new(Vec::new()),
+});
+
+impl Health {
+	/// Called by the loop of a printer to show it is not stuck.
+	pub fn beat(&self, printer: &str) {
+		self.heartbeats.lock().unwrap().insert(printer.to_string(), Utc::now().timestamp());
+	}
+
+	pub fn set_sink_problems(&self, problems: Vec<String>) {
+		*self.sink_problems.lock().unwrap() = problems;
+	}
+
+	/// Healthy while the loops of all printers ran within the given number
+	/// of seconds.
+	pub fn healthy(&self, max_age_secs: u64) -> Result<String, String> {
+		let now = Utc::now().timestamp();
+		let heartbeats = self.heartbeats.lock().unwrap();
+		let mut printers: Vec<_> = heartbeats.iter()
+			.map(|(printer, heartbeat)| (printer, now - heartbeat))
+			.collect();
+		printers.sort();
+
+		let stale: Vec<String> = printers.iter()
+			.filter(|(_, age)| *age > max_age_secs as i64)
+			.map(|(printer, age)| format!("loop of {} inactive for {} seconds", printer, age))
+			.collect();
+		if !stale.is_empty() {
+			return Err(stale.join("\n") + "\n")
+		}
+		let active: Vec<String> = printers.iter()
+			.map(|(printer, age)| format!("loop of {} active {} seconds ago", printer, age))
+			.collect();
+		Ok(format!("ok\n{}\n", active.join("\n")))
+	}
+
+	/// Ready once a printer is reachable with destinations registered and
+	/// all sinks have the settings they need. Printers that are switched off
+	/// are listed without failing the check.
+	pub fn ready(&self) -> Result<String, String> {
+		let mut printers: Vec<String> = self.heartbeats.lock().unwrap().keys().cloned().collect();
+		printers.sort();
+
+		let mut offline = Vec::new();
+		for printer in &printers {
+			if METRICS.printer_reachable.with_label_values(&[printer]).get() == 0 {
+				offline.push(format!("printer {} not reachable", printer));
+			} else if METRICS.destinations_registered.with_label_values(&[printer]).get() == 0 {
+				offline.push(format!("no destinations registered on {}", printer));
+			}
+		}
+
+		let mut problems = self.sink_problems.lock().unwrap().clone();
+		if offline.len() == printers.len() {
+			problems.splice(0..0, offline.iter().cloned());
+			if printers.is_empty() {
+				problems.insert(0, "no printer connected".to_string());
+			}
+		}
+
+		match problems.is_empty() {
+			true => Ok(format!("ok\n{}", offline.iter().map(|line| line.clone() + "\n").collect::<String>())),
+			false => Err(problems.join("\n") + "\n"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// the metrics are global, every test uses its own printer names
+	fn health(printers: &[(&str, i64, i64)], sink_problems: &[&str]) -> Health {
+		let health = Health {
+			heartbeats: Mutex::new(HashMap::new()),
+			sink_problems: Mutex::new(sink_problems.iter().map(|problem| problem.to_string()).collect()),
+		};
+		for (printer, reachable, registered) in printers {
+			health.beat(printer);
+			METRICS.printer_reachable.with_label_values(&[printer]).set(*reachable);
+			METRICS.destinations_registered.with_label_values(&[printer]).set(*registered);
+		}
+		health
+	}
+
+	#[test]
+	fn ready_with_all_printers() {
+		let health = health(&[("ready-a", 1, 2), ("ready-b", 1, 1)], &[]);
+		assert_eq!(health.ready(), Ok("ok\n".to_string()));
+	}
+
+	#[test]
+	fn lists_unreachable_printers_without_failing() {
+		let health = health(&[("partial-a", 1, 2), ("partial-b", 0, 0)], &[]);
+		assert_eq!(health.ready(), Ok("ok\nprinter partial-b not reachable\n".to_string()));
+	}
+
+	#[test]
+	fn fails_without_reachable_printer() {
+		let offline = health(&[("offline-a", 0, 0), ("offline-b", 1, 0)], &[]);
+		assert_eq!(offline.ready(), Err("printer offline-a not reachable\nno destinations registered on offline-b\n".to_string()));
+		assert_eq!(health(&[], &[]).ready(), Err("no printer connected\n".to_string()));
+	}
+
+	#[test]
+	fn fails_with_sink_problems() {
+		let health = health(&[("sinks-a", 1, 1)], &["Office: SENDGRID_API_KEY is not set", "Home: MAIL_TO is not set"]);
+		assert_eq!(health.ready(), Err("Office: SENDGRID_API_KEY is not set\nHome: MAIL_TO is not set\n".to_string()));
+	}
+
+	#[test]
+	fn reports_stale_loops() {
+		let health = health(&[("stale-a", 1, 1)], &[]);
+		health.heartbeats.lock().unwrap().insert("stale-b".to_string(), Utc::now().timestamp() - 700);
+		assert_eq!(health.healthy(600), Err("loop of stale-b inactive for 700 seconds\n".to_string()));
+		let healthy = health.healthy(800).unwrap();
+		assert!(healthy.starts_with("ok\nloop of stale-a active "), "{}", healthy);
+	}
+}
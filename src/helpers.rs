@@ -1,30 +1,106 @@
-use crate::objects::{ScanSettings, ScanStatus, ToneMap, WalkupScanToCompSettings};
+use std::{thread, time};
+use clap::ValueEnum;
+use serde::Deserialize;
+use crate::config::DestinationConfig;
+use crate::health::HEALTH;
+use crate::history::History;
+use crate::hp_api::HpApi;
+use crate::jpeg::set_jpeg_height;
+use crate::metrics::METRICS;
+use crate::objects::{ApiError, ScanSettings, ScanStatus, ToneMap, WalkupScanToCompSettings};
+use crate::outbox::Outbox;
+use crate::processing::{Document, ScannedPage};
+use crate::sinks::deliver;
 
-pub fn create_job(status: ScanStatus, settings: WalkupScanToCompSettings) -> ScanSettings {
+pub fn create_job(status: ScanStatus, settings: WalkupScanToCompSettings) -> Result<ScanSettings, ApiError> {
 	let source = match status.adf_state.as_str() {
 		"Empty" => {"Platen"}
 		"Loaded" => {"Adf"},
-		_ => {panic!("Unexpected ADF State {}", status.adf_state)}
+		_ => return Err(ApiError::new(&format!("Unexpected ADF State {}", status.adf_state)))
 	};
 
-	let (format, content) = match settings.shortcut.as_str() {
-		"SaveDocument1" => { ("Pdf", "Document") },
-		"SavePhoto1" => { ("Pdf", "Photo" ) },
-		_ => {panic!("Unexpected shortcut {}", settings.shortcut)}
+	let content = match settings.shortcut.as_str() {
+		"SaveDocument1" => { "Document" },
+		"SavePhoto1" => { "Photo" },
+		_ => return Err(ApiError::new(&format!("Unexpected shortcut {}", settings.shortcut)))
 	};
 
-	log::info!("Using configuration source: {}; content: {}; format: {}", source, content, format);
+	Ok(job_settings(source, content, 200, "Color"))
+}
+
+/// Color space of scans started over HTTP or the command line.
+#[derive(Deserialize, ValueEnum, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Color {
+	#[default]
+	Color,
+	Gray,
+}
+
+impl Color {
+	pub fn color_space(self) -> &'static str {
+		match self {
+			Color::Color => "Color",
+			Color::Gray => "Gray",
+		}
+	}
+}
+
+#[derive(Deserialize, ValueEnum, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+	/// The document feeder if loaded, the flatbed otherwise
+	#[default]
+	Auto,
+	Platen,
+	Adf,
+}
+
+impl Source {
+	pub fn input_source(self, api: &HpApi) -> Result<&'static str, ApiError> {
+		match self {
+			Source::Auto => match api.get_scanner_status()?.adf_state.as_str() {
+				"Loaded" => Ok("Adf"),
+				_ => Ok("Platen"),
+			},
+			Source::Platen => Ok("Platen"),
+			Source::Adf => Ok("Adf"),
+		}
+	}
+}
+
+#[derive(Deserialize, ValueEnum, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Content {
+	#[default]
+	Document,
+	Photo,
+}
+
+impl Content {
+	pub fn content_type(self) -> &'static str {
+		match self {
+			Content::Document => "Document",
+			Content::Photo => "Photo",
+		}
+	}
+}
+
+/// Settings of a JPEG scan job of a full A4 page.
+pub fn job_settings(source: &str, content: &str, resolution: i16, color_space: &str) -> ScanSettings {
+	log::info!("Using configuration source: {}; content: {}; format: Jpeg; resolution: {}; color space: {}",
+		source, content, resolution, color_space);
 
 	ScanSettings {
-		x_resolution: 200,
-		y_resolution: 200,
+		x_resolution: resolution,
+		y_resolution: resolution,
 		x_start: 33,
 		y_start: 0,
 		width: 2481,
 		height: 3507,
-		format: format.to_string(),
+		format: "Jpeg".to_string(),
 		compression_q_factor: 0,
-		color_space: "Color".to_string(),
+		color_space: color_space.to_string(),
 		bit_depth: 8,
 		input_source: source.to_string(),
 		gray_rendering: "NTSC".to_string(),
@@ -42,7 +118,76 @@ pub fn create_job(status: ScanStatus, settings: WalkupScanToCompSettings) -> Sca
 	}
 }
 
-pub fn create_filename() -> String {
-	let now = chrono::offset::Local::now();
-	format!("Scan_{}.pdf", now.format("%FZ%H-%M"))
+/// Waits for the pages of a created scan job and downloads them until the
+/// job is finished. `heartbeat` is only set by the loop of the printer, so
+/// scans started elsewhere cannot hide a stuck loop.
+pub fn download_pages(api: &HpApi, job_location: &String, destination: &str, heartbeat: bool) -> Result<Vec<ScannedPage>, ApiError> {
+	tracing::Span::current().record("job", job_location.as_str());
+	let mut pages: Vec<ScannedPage> = Vec::new();
+
+	loop {
+		log::debug!("Waiting for scanner");
+		if heartbeat {
+			HEALTH.beat(api.name());
+		}
+		let job_info = api.get_job_with_url(job_location)?;
+
+		if job_info.state == "Completed" || job_info.state == "Canceled" { break }
+
+		let first_page = match job_info.scan_job.pre_scan_page.first() {
+			Some(page) => page,
+			None if pages.is_empty() => return Err(ApiError::new("Scan job has no pages")),
+			None => {
+				thread::sleep(time::Duration::from_millis(300));
+				continue
+			}
+		};
+
+		let already_downloaded = pages.iter()
+			.any(|page| page.number == first_page.number);
+
+		if first_page.state == "PreparingScan" || already_downloaded {
+			// sleeping a bit to not hammer the printer
+			thread::sleep(time::Duration::from_millis(300));
+			continue
+		}
+
+		if first_page.state == "ReadyToUpload" {
+			log::info!("Downloading page {} from scanner", first_page.number);
+			let mut image = api.download_page(&first_page.binary_url)
+				.map_err(|e| ApiError::new(&e.to_string()))?;
+			log::info!("Download successful");
+			METRICS.pages_scanned.with_label_values(&[api.name(), destination]).inc();
+
+			// the real page height is only known after the upload
+			let job_info = api.get_job_with_url(job_location)?;
+			if let Some(post_scan_page) = job_info.scan_job.post_scan_page.iter()
+				.find(|page| page.number == first_page.number && page.total_lines > 0) {
+				set_jpeg_height(&mut image, post_scan_page.total_lines as u16);
+			}
+
+			pages.push(ScannedPage {
+				number: first_page.number,
+				image,
+				orientation: first_page.image_orientation.clone(),
+			});
+		}
+	}
+
+	if pages.is_empty() {
+		return Err(ApiError::new("Scan job finished without any pages"))
+	}
+
+	log::info!("Scanned {} pages successfully", pages.len());
+	Ok(pages)
+}
+
+/// Archives a scanned document and queues it for the sinks of its
+/// destination, delivering it right away if the outbox is not writable.
+pub fn submit(document: &Document, destination: &DestinationConfig, outbox: &Outbox, history: &History) {
+	history.archive(document);
+	if let Err(e) = outbox.enqueue(document) {
+		log::error!("Error queueing {}, delivering without retries: {}", document.filename, e);
+		deliver(document, destination, history);
+	}
 }
@@ -0,0 +1,508 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use chrono::{DateTime, Duration, Local, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use crate::config::HistoryConfig;
+use crate::processing::Document;
+use crate::sinks::SinkError;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS scans (
+	id INTEGER PRIMARY KEY,
+	started TEXT NOT NULL,
+	destination_id TEXT NOT NULL,
+	destination TEXT NOT NULL,
+	shortcut TEXT NOT NULL DEFAULT '',
+	source TEXT NOT NULL DEFAULT '',
+	pages INTEGER NOT NULL DEFAULT 0,
+	size INTEGER NOT NULL DEFAULT 0,
+	duration_ms INTEGER NOT NULL DEFAULT 0,
+	status TEXT NOT NULL,
+	error TEXT
+);
+CREATE TABLE IF NOT EXISTS deliveries (
+	id INTEGER PRIMARY KEY,
+	scan_id INTEGER REFERENCES scans(id) ON DELETE CASCADE,
+	attempted TEXT NOT NULL,
+	filename TEXT NOT NULL,
+	sink TEXT NOT NULL,
+	success INTEGER NOT NULL,
+	error TEXT
+);
+CREATE TABLE IF NOT EXISTS documents (
+	id INTEGER PRIMARY KEY,
+	scan_id INTEGER NOT NULL REFERENCES scans(id) ON DELETE CASCADE,
+	filename TEXT NOT NULL,
+	size INTEGER NOT NULL,
+	pages INTEGER NOT NULL,
+	thumbnail INTEGER NOT NULL,
+	document TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS scans_started ON scans(started);
+CREATE INDEX IF NOT EXISTS documents_scan ON documents(scan_id);
+CREATE INDEX IF NOT EXISTS deliveries_scan ON deliveries(scan_id);
+";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ScanRecord {
+	pub id: i64,
+	pub started: DateTime<Local>,
+	pub destination_id: String,
+	pub destination: String,
+	pub shortcut: String,
+	pub source: String,
+	pub pages: i64,
+	pub size: i64,
+	pub duration_ms: i64,
+	/// `running`, `completed` or `failed`
+	pub status: String,
+	pub error: Option<String>,
+	pub deliveries: Vec<DeliveryRecord>,
+	/// Archived documents, only kept with an archive directory configured
+	pub documents: Vec<DocumentRecord>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DocumentRecord {
+	pub id: i64,
+	pub filename: String,
+	pub size: i64,
+	pub pages: i64,
+	pub thumbnail: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeliveryRecord {
+	pub attempted: DateTime<Local>,
+	pub filename: String,
+	pub sink: String,
+	pub success: bool,
+	pub error: Option<String>,
+}
+
+/// Restricts the scans returned by `History::scans`.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+	pub destination: Option<String>,
+	pub since: Option<DateTime<Local>>,
+	pub status: Option<String>,
+	pub limit: Option<usize>,
+}
+
+/// Log of scan jobs and their deliveries. Errors while recording are only
+/// logged, the history never gets in the way of a scan.
+pub struct History {
+	connection: Mutex<Connection>,
+	retention_days: u32,
+	archive: Option<PathBuf>,
+}
+
+impl History {
+	pub fn open(config: &HistoryConfig) -> Result<History, HistoryError> {
+		let connection = Connection::open(&config.database)
+			.map_err(|e| HistoryError::new(&format!("Error opening {}: {}", config.database, e)))?;
+		connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+		connection.execute_batch(SCHEMA)?;
+
+		let history = History {
+			connection: Mutex::new(connection),
+			retention_days: config.retention_days,
+			archive: config.archive.as_ref().map(PathBuf::from),
+		};
+		history.apply_retention();
+		Ok(history)
+	}
+
+	/// Records the start of a scan job and returns its id.
+	pub fn start_scan(&self, destination_id: &str, destination: &str) -> Option<i64> {
+		let connection = self.connection.lock().unwrap();
+		let result = connection.execute(
+			"INSERT INTO scans (started, destination_id, destination, status) VALUES (?1, ?2, ?3, 'running')",
+			params![timestamp(Local::now()), destination_id, destination]);
+
+		match result {
+			Ok(_) => Some(connection.last_insert_rowid()),
+			Err(e) => {
+				log::error!("Error recording scan in history: {}", e);
+				None
+			}
+		}
+	}
+
+	pub fn update_settings(&self, id: Option<i64>, shortcut: &str, source: &str) {
+		let Some(id) = id else { return };
+		self.execute("UPDATE scans SET shortcut = ?2, source = ?3 WHERE id = ?1",
+			params![id, shortcut, source]);
+	}
+
+	pub fn finish_scan(&self, id: Option<i64>, pages: usize, size: usize, result: Result<(), String>) {
+		let Some(id) = id else { return };
+		let (status, error) = match result {
+			Ok(_) => ("completed", None),
+			Err(e) => ("failed", Some(e)),
+		};
+
+		let started = self.connection.lock().unwrap()
+			.query_row("SELECT started FROM scans WHERE id = ?1", [id], |row| row.get::<_, String>(0))
+			.optional()
+			.ok()
+			.flatten()
+			.and_then(|started| DateTime::parse_from_rfc3339(&started).ok());
+		let duration = started
+			.map(|started| (Utc::now() - started.with_timezone(&Utc)).num_milliseconds())
+			.unwrap_or_default();
+
+		self.execute("UPDATE scans SET pages = ?2, size = ?3, duration_ms = ?4, status = ?5, error = ?6 WHERE id = ?1",
+			params![id, pages as i64, size as i64, duration, status, error]);
+		self.apply_retention();
+	}
+
+	pub fn record_delivery(&self, scan_id: Option<i64>, filename: &str, sink: &str, result: &Result<(), SinkError>) {
+		let error = result.as_ref().err().map(|e| e.to_string());
+		self.execute(
+			"INSERT INTO deliveries (scan_id, attempted, filename, sink, success, error) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+			params![scan_id, timestamp(Local::now()), filename, sink, result.is_ok(), error]);
+	}
+
+	/// Keeps the document and its thumbnail in the archive directory, so it
+	/// can be downloaded and sent again later.
+	pub fn archive(&self, document: &Document) {
+		let (Some(archive), Some(scan_id)) = (&self.archive, document.scan_id) else { return };
+
+		let id = {
+			let connection = self.connection.lock().unwrap();
+			let result = serde_json::to_string(document)
+				.map_err(|e| e.to_string())
+				.and_then(|json| connection.execute(
+					"INSERT INTO documents (scan_id, filename, size, pages, thumbnail, document) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+					params![scan_id, document.filename, document.content.len() as i64, document.page_count as i64, document.thumbnail.is_some(), json])
+					.map_err(|e| e.to_string()));
+			match result {
+				Ok(_) => connection.last_insert_rowid(),
+				Err(e) => {
+					log::error!("Error archiving {}: {}", document.filename, e);
+					return
+				}
+			}
+		};
+
+		let directory = archive.join(scan_id.to_string());
+		let result = fs::create_dir_all(&directory)
+			.and_then(|_| fs::write(directory.join(format!("{}.pdf", id)), &document.content))
+			.and_then(|_| match &document.thumbnail {
+				Some(thumbnail) => fs::write(directory.join(format!("{}.jpg", id)), thumbnail),
+				None => Ok(()),
+			});
+		if let Err(e) = result {
+			log::error!("Error archiving {}: {}", document.filename, e);
+		}
+	}
+
+	/// Loads an archived document including its content.
+	pub fn document(&self, id: i64) -> Result<Option<Document>, HistoryError> {
+		let Some(archive) = &self.archive else { return Ok(None) };
+		let row = self.connection.lock().unwrap()
+			.query_row("SELECT scan_id, document FROM documents WHERE id = ?1", [id],
+				|row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))
+			.optional()?;
+		let Some((scan_id, json)) = row else { return Ok(None) };
+
+		let mut document: Document = serde_json::from_str(&json)
+			.map_err(|e| HistoryError::new(&e.to_string()))?;
+		document.content = fs::read(archive.join(scan_id.to_string()).join(format!("{}.pdf", id)))
+			.map_err(|e| HistoryError::new(&format!("Error reading archived document {}: {}", id, e)))?;
+		Ok(Some(document))
+	}
+
+	pub fn thumbnail(&self, id: i64) -> Option<Vec<u8>> {
+		let archive = self.archive.as_ref()?;
+		let scan_id = self.connection.lock().unwrap()
+			.query_row("SELECT scan_id FROM documents WHERE id = ?1", [id], |row| row.get::<_, i64>(0))
+			.ok()?;
+		fs::read(archive.join(scan_id.to_string()).join(format!("{}.jpg", id))).ok()
+	}
+
+	fn execute(&self, sql: &str, params: impl rusqlite::Params) {
+		if let Err(e) = self.connection.lock().unwrap().execute(sql, params) {
+			log::error!("Error updating history: {}", e);
+		}
+	}
+
+	/// Deletes scans older than the retention period together with their deliveries.
+	pub fn apply_retention(&self) {
+		let days = self.retention_days;
+		if days == 0 {
+			return
+		}
+		let cutoff = timestamp(Local::now() - Duration::days(days as i64));
+		let connection = self.connection.lock().unwrap();
+
+		if let Some(archive) = &self.archive {
+			let expired = connection.prepare("SELECT id FROM scans WHERE started < ?1")
+				.and_then(|mut statement| statement.query_map([&cutoff], |row| row.get::<_, i64>(0))?
+					.collect::<Result<Vec<i64>, rusqlite::Error>>())
+				.unwrap_or_default();
+			for id in expired {
+				let directory = archive.join(id.to_string());
+				if directory.exists() {
+					if let Err(e) = fs::remove_dir_all(&directory) {
+						log::error!("Error removing {}: {}", directory.display(), e);
+					}
+				}
+			}
+		}
+
+		match connection.execute("DELETE FROM scans WHERE started < ?1", [&cutoff]) {
+			Ok(0) => {}
+			Ok(deleted) => log::info!("Removed {} scans older than {} days from history", deleted, days),
+			Err(e) => log::error!("Error applying history retention: {}", e),
+		}
+	}
+
+	/// Returns the matching scans, newest first.
+	pub fn scans(&self, filter: &HistoryFilter) -> Result<Vec<ScanRecord>, HistoryError> {
+		let connection = self.connection.lock().unwrap();
+		let mut statement = connection.prepare(&format!(
+			"SELECT {} FROM scans
+			WHERE (?1 IS NULL OR destination = ?1) AND (?2 IS NULL OR started >= ?2) AND (?3 IS NULL OR status = ?3)
+			ORDER BY started DESC, id DESC LIMIT ?4", SCAN_COLUMNS))?;
+
+		let limit = filter.limit.map(|limit| limit as i64).unwrap_or(-1);
+		let since = filter.since.map(timestamp);
+		let mut scans = statement.query_map(params![filter.destination, since, filter.status, limit], scan_record)?
+			.collect::<Result<Vec<ScanRecord>, rusqlite::Error>>()?;
+		attach_records(&connection, &mut scans)?;
+		Ok(scans)
+	}
+
+	/// Returns a single scan with its deliveries and documents.
+	pub fn scan(&self, id: i64) -> Result<Option<ScanRecord>, HistoryError> {
+		let connection = self.connection.lock().unwrap();
+		let scan = connection.query_row(&format!("SELECT {} FROM scans WHERE id = ?1", SCAN_COLUMNS), [id], scan_record)
+			.optional()?;
+		let Some(mut scan) = scan else { return Ok(None) };
+		attach_records(&connection, std::slice::from_mut(&mut scan))?;
+		Ok(Some(scan))
+	}
+}
+
+const SCAN_COLUMNS: &str = "id, started, destination_id, destination, shortcut, source, pages, size, duration_ms, status, error";
+
+fn scan_record(row: &Row) -> rusqlite::Result<ScanRecord> {
+	Ok(ScanRecord {
+		id: row.get(0)?,
+		started: parse_time(&row.get::<_, String>(1)?),
+		destination_id: row.get(2)?,
+		destination: row.get(3)?,
+		shortcut: row.get(4)?,
+		source: row.get(5)?,
+		pages: row.get(6)?,
+		size: row.get(7)?,
+		duration_ms: row.get(8)?,
+		status: row.get(9)?,
+		error: row.get(10)?,
+		deliveries: Vec::new(),
+		documents: Vec::new(),
+	})
+}
+
+/// Fills in the deliveries and archived documents of the scans.
+fn attach_records(connection: &Connection, scans: &mut [ScanRecord]) -> Result<(), HistoryError> {
+	let mut statement = connection.prepare(
+		"SELECT attempted, filename, sink, success, error FROM deliveries WHERE scan_id = ?1 ORDER BY id")?;
+	for scan in scans.iter_mut() {
+		scan.deliveries = statement.query_map([scan.id], |row| {
+			Ok(DeliveryRecord {
+				attempted: parse_time(&row.get::<_, String>(0)?),
+				filename: row.get(1)?,
+				sink: row.get(2)?,
+				success: row.get(3)?,
+				error: row.get(4)?,
+			})
+		})?.collect::<Result<Vec<DeliveryRecord>, rusqlite::Error>>()?;
+	}
+
+	let mut statement = connection.prepare(
+		"SELECT id, filename, size, pages, thumbnail FROM documents WHERE scan_id = ?1 ORDER BY id")?;
+	for scan in scans.iter_mut() {
+		scan.documents = statement.query_map([scan.id], |row| {
+			Ok(DocumentRecord {
+				id: row.get(0)?,
+				filename: row.get(1)?,
+				size: row.get(2)?,
+				pages: row.get(3)?,
+				thumbnail: row.get(4)?,
+			})
+		})?.collect::<Result<Vec<DocumentRecord>, rusqlite::Error>>()?;
+	}
+	Ok(())
+}
+
+/// Times are stored in UTC so they sort as text.
+fn timestamp(time: DateTime<Local>) -> String {
+	time.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn parse_time(value: &str) -> DateTime<Local> {
+	DateTime::parse_from_rfc3339(value)
+		.map(|time| time.with_timezone(&Local))
+		.unwrap_or_default()
+}
+
+/// Quotes a CSV field if needed.
+fn csv_field(value: &str) -> String {
+	match value.contains([',', '"', '\n', '\r']) {
+		true => format!("\"{}\"", value.replace('"', "\"\"")),
+		false => value.to_string(),
+	}
+}
+
+/// One line per scan, the deliveries are summarized in a single column.
+pub fn to_csv(scans: &[ScanRecord]) -> String {
+	let mut csv = String::from("id,started,destination_id,destination,shortcut,source,pages,size,duration_ms,status,error,deliveries\n");
+	for scan in scans {
+		let deliveries = scan.deliveries.iter()
+			.map(|delivery| match &delivery.error {
+				Some(error) => format!("{} {}: {}", delivery.filename, delivery.sink, error),
+				None => format!("{} {}: ok", delivery.filename, delivery.sink),
+			})
+			.collect::<Vec<String>>()
+			.join("; ");
+		let fields = [
+			scan.id.to_string(),
+			scan.started.to_rfc3339(),
+			scan.destination_id.clone(),
+			scan.destination.clone(),
+			scan.shortcut.clone(),
+			scan.source.clone(),
+			scan.pages.to_string(),
+			scan.size.to_string(),
+			scan.duration_ms.to_string(),
+			scan.status.clone(),
+			scan.error.clone().unwrap_or_default(),
+			deliveries,
+		];
+		csv.push_str(&fields.iter().map(|field| csv_field(field)).collect::<Vec<String>>().join(","));
+		csv.push('\n');
+	}
+	csv
+}
+
+pub fn to_table(scans: &[ScanRecord]) -> String {
+	let mut table = format!("{:<6} {:<20} {:<20} {:<9} {:>5} {:>10} {:>8} {}\n",
+		"ID", "STARTED", "DESTINATION", "STATUS", "PAGES", "SIZE", "SECONDS", "DELIVERIES");
+	for scan in scans {
+		let delivered = scan.deliveries.iter().filter(|delivery| delivery.success).count();
+		table.push_str(&format!("{:<6} {:<20} {:<20} {:<9} {:>5} {:>10} {:>8.1} {}/{}{}\n",
+			scan.id,
+			scan.started.format("%Y-%m-%d %H:%M:%S"),
+			scan.destination,
+			scan.status,
+			scan.pages,
+			scan.size,
+			scan.duration_ms as f64 / 1000.0,
+			delivered,
+			scan.deliveries.len(),
+			scan.error.as_ref().map(|error| format!(" {}", error)).unwrap_or_default()));
+	}
+	table
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryError {
+	pub details: String,
+}
+
+impl HistoryError {
+	pub fn new(msg: &str) -> HistoryError {
+		HistoryError{details: msg.to_string()}
+	}
+}
+
+impl From<rusqlite::Error> for HistoryError {
+	fn from(e: rusqlite::Error) -> Self {
+		HistoryError::new(&e.to_string())
+	}
+}
+
+impl fmt::Display for HistoryError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.details)
+	}
+}
+
+impl std::error::Error for HistoryError {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn history(retention_days: u32) -> History {
+		History::open(&HistoryConfig {
+			database: ":memory:".to_string(),
+			retention_days,
+			archive: None,
+		}).unwrap()
+	}
+
+	fn age(history: &History, id: i64, days: i64) {
+		history.connection.lock().unwrap()
+			.execute("UPDATE scans SET started = ?1 WHERE id = ?2", (timestamp(Local::now() - Duration::days(days)), id))
+			.unwrap();
+	}
+
+	#[test]
+	fn removes_expired_scans() {
+		let history = history(30);
+		let old = history.start_scan("a", "Old").unwrap();
+		let recent = history.start_scan("b", "Recent").unwrap();
+		age(&history, old, 31);
+		age(&history, recent, 29);
+		history.apply_retention();
+
+		let scans = history.scans(&HistoryFilter::default()).unwrap();
+		assert_eq!(scans.iter().map(|scan| scan.id).collect::<Vec<i64>>(), vec![recent]);
+	}
+
+	#[test]
+	fn zero_retention_keeps_scans() {
+		let history = history(0);
+		let id = history.start_scan("a", "Old").unwrap();
+		age(&history, id, 10_000);
+		history.apply_retention();
+
+		assert_eq!(history.scans(&HistoryFilter::default()).unwrap().len(), 1);
+	}
+
+	#[test]
+	fn finds_scan_by_id() {
+		let history = history(0);
+		let first = history.start_scan("a", "First").unwrap();
+		history.start_scan("b", "Second").unwrap();
+		history.record_delivery(Some(first), "scan.pdf", "Mail", &Ok(()));
+
+		let scan = history.scan(first).unwrap().unwrap();
+		assert_eq!(scan.destination, "First");
+		assert_eq!(scan.deliveries.len(), 1);
+		assert!(history.scan(first + 100).unwrap().is_none());
+	}
+
+	#[test]
+	fn quotes_csv_fields() {
+		let history = history(0);
+		let id = history.start_scan("a", "Scan, \"Office\"").unwrap();
+		history.finish_scan(Some(id), 2, 1024, Err("line\nbreak".to_string()));
+		history.record_delivery(Some(id), "scan.pdf", "Mail", &Err(SinkError::new("refused")));
+		let scans = history.scans(&HistoryFilter::default()).unwrap();
+
+		let csv = to_csv(&scans);
+		let (header, line) = csv.split_once('\n').unwrap();
+		assert_eq!(header.split(',').count(), 12);
+		assert!(line.starts_with(&format!("{},{},a,\"Scan, \"\"Office\"\"\",", id, scans[0].started.to_rfc3339())));
+		assert!(line.contains(",2,1024,"));
+		assert!(line.ends_with(",failed,\"line\nbreak\",scan.pdf Mail: refused\n"));
+	}
+}
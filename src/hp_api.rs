@@ -1,25 +1,30 @@
-use std::fs::File;
-use std::io::{copy, Cursor};
-use std::time::Duration;
-use reqwest::blocking::{ClientBuilder, Client};
+use std::time::{Duration, Instant};
+use reqwest::blocking::{ClientBuilder, Client, RequestBuilder, Response};
 use reqwest::{StatusCode, Url};
 use uuid::Uuid;
 use yaserde::de::from_str;
 use yaserde::ser::to_string;
-use crate::objects::{AddDestinationError, DeleteDestinationError, GetDestinationError, DownloadError, WalkupDestination, WalkupDestinations, WalkupScanToCompEvent, ApiError, EventTable, Job, ScanSettings, ScanStatus};
+use crate::metrics::METRICS;
+use crate::objects::{AddDestinationError, DeleteDestinationError, GetDestinationError, DownloadError, WalkupDestination, WalkupDestinations, WalkupScanToCompEvent, ApiError, EventTable, Job, ScanSettings, ScanStatus, ScanCaps, ProductConfig, PrinterInfo};
 
 pub struct HpApi {
 	client: Client,
 	base_url: Url,
+	/// Printer label of the metrics
+	name: String,
 	pub active_destinations: Vec<Uuid>,
 	last_known_etag: Option<String>
 }
 
 impl<'a> HpApi {
 	pub fn new(base_url: Url) -> HpApi {
+		HpApi::with_timeout(base_url, Duration::from_secs(3 * 60))
+	}
+
+	pub fn with_timeout(base_url: Url, timeout: Duration) -> HpApi {
 		let client = ClientBuilder::new()
 			.http1_title_case_headers()
-			.timeout(Duration::from_secs(3 * 60))
+			.timeout(timeout)
 			.build()
 			.expect("Error building the HP API Client");
 
@@ -27,12 +32,67 @@ impl<'a> HpApi {
 
 		HpApi {
 			client,
+			name: base_url.host_str().unwrap_or_default().to_string(),
 			base_url,
 			active_destinations: Vec::new(),
 			last_known_etag: None
 		}
 	}
 
+	pub fn named(mut self, name: &str) -> HpApi {
+		self.name = name.to_string();
+		self
+	}
+
+	pub fn name(&'a self) -> &'a str {
+		&self.name
+	}
+
+	/// Sends the request and records its duration by endpoint and status.
+	fn send(&'a self, endpoint: &str, request: RequestBuilder) -> reqwest::Result<Response> {
+		let start = Instant::now();
+		let response = request.send();
+		let status = match &response {
+			Ok(response) => response.status().as_u16().to_string(),
+			Err(_) => "error".to_string(),
+		};
+		METRICS.api_requests
+			.with_label_values(&[&self.name, endpoint, &status])
+			.observe(start.elapsed().as_secs_f64());
+		METRICS.printer_reachable.with_label_values(&[&self.name]).set(response.is_ok() as i64);
+		response
+	}
+
+	pub fn base_url(&'a self) -> &'a Url {
+		&self.base_url
+	}
+
+	pub fn host(&'a self) -> String {
+		self.base_url.host_str()
+			.unwrap_or_default()
+			.to_string()
+	}
+
+	/// Model and serial number are left empty if the printer does not
+	/// report them.
+	pub fn printer_info(&'a self) -> PrinterInfo {
+		let mut info = PrinterInfo {
+			host: self.host(),
+			..PrinterInfo::default()
+		};
+
+		match self.get_product_config() {
+			Ok(config) => {
+				info.model = config.product_information.make_and_model;
+				info.serial = config.product_information.serial_number;
+				log::info!("Connected to {} with serial number {}", info.model, info.serial);
+			}
+			Err(e) => log::warn!("Could not read printer model and serial number: {}", e),
+		}
+
+		info
+	}
+
 	pub fn cleanup(&'a mut self) {
 		for destination in self.active_destinations.clone() {
 			let _ = self.delete_destination(destination);
@@ -50,7 +110,7 @@ impl<'a> HpApi {
 		let url = self.base_url.join("Scan/Status")
 			.expect("Error generating URL");
 
-		match client.get(url).send() {
+		match self.send("Scan/Status", client.get(url)) {
 			Ok(_) => {
 				log::debug!("Printer reachable!");
 				true
@@ -62,23 +122,23 @@ impl<'a> HpApi {
 		}
 	}
 
-	#[allow(dead_code)]
 	pub fn get_walkup_destinations(&'a self) -> Result<WalkupDestinations, GetDestinationError> {
 		log::debug!("Making request for WalkupScanToCompDestinations");
 
 		let url = self.base_url.join("WalkupScanToComp/WalkupScanToCompDestinations")
 			.expect("Error generating URL");
 
-		let resp = self.client.get(url)
-			.send()
-			.expect("Error making GET WalkupScanToCompDestinations request")
-			.text()
-			.expect("Answer to GET WalkupScanToCompDestinations request did not contain text");
+		let resp = self.send("WalkupScanToCompDestinations", self.client.get(url))
+			.and_then(|response| response.text())
+			.map_err(|e| {
+				log::error!("Error requesting walkup destinations: {}", e);
+				GetDestinationError
+			})?;
 		let deser: Result<WalkupDestinations, String> = from_str(&resp);
 
 		match deser {
 			Ok(dests) => {
-				log::debug!("Got list of walkup destinations with {} destinations", dests.destinations.iter().count());
+				log::debug!("Got list of walkup destinations with {} destinations", dests.destinations.len());
 				Ok(dests)
 			}
 			Err(_) => {
@@ -95,11 +155,12 @@ impl<'a> HpApi {
 			.join(uuid.to_string().as_str())
 			.expect("Error generating URL");
 
-		let resp = self.client.get(url)
-			.send()
-			.expect("Error making GET WalkupScanToCompDestinations request")
-			.text()
-			.expect("Answer to GET WalkupScanToCompDestinations request did not contain text");
+		let resp = self.send("WalkupScanToCompDestinations", self.client.get(url))
+			.and_then(|response| response.text())
+			.map_err(|e| {
+				log::error!("Error requesting walkup destination {}: {}", uuid, e);
+				GetDestinationError
+			})?;
 		let deser: Result<WalkupDestination, String> = from_str(&resp);
 
 		match deser {
@@ -120,32 +181,35 @@ impl<'a> HpApi {
 		let url = self.base_url.join("/WalkupScanToComp/WalkupScanToCompDestinations")
 			.expect("Error generating URL");
 
-		let response = self.client.post(url)
+		let request = self.client.post(url)
 			.header("Content-Type", "text/xml")
-			.body(str)
-			.send()
-			.expect("Error sending POST WalkupScanToCompDestinations request");
+			.body(str);
+		let response = self.send("WalkupScanToCompDestinations", request)
+			.map_err(|e| {
+				log::error!("Error sending POST WalkupScanToCompDestinations request: {}", e);
+				AddDestinationError
+			})?;
 
 		match response.status() {
 			StatusCode::CREATED => {
 				let location = response
 					.headers()
 					.get("Location")
-					.expect("Missing Location header in response")
-					.to_str()
-					.expect("Could not map Location header to string");
+					.and_then(|location| location.to_str().ok())
+					.ok_or_else(|| {
+						log::error!("Missing Location header in response");
+						AddDestinationError
+					})?;
 
 				log::info!("Successfully created new WalkupScanToCompDestinations with name {}", new_destination.name);
 				log::debug!("Using location URL: {} to generate UUID", location);
 
-				let url_parts = location.split("/")
-					.collect::<Vec<&str>>();
-
-				let uuid_string = url_parts.last()
-					.expect("Could not get last part of Location URL");
-
+				let uuid_string = location.rsplit('/').next().unwrap_or_default();
 				let uuid = Uuid::parse_str(uuid_string)
-					.expect("Location URL did not contain a valid UUID");
+					.map_err(|_| {
+						log::error!("Location URL {} did not contain a valid UUID", location);
+						AddDestinationError
+					})?;
 
 				self.active_destinations.push(uuid);
 
@@ -168,15 +232,15 @@ impl<'a> HpApi {
 		let url = self.base_url.join(&path)
 			.expect("Error generating URL");
 
-		let response = self.client.delete(url)
-			.send()
-			.expect("Error sending DELETE WalkupScanToCompDestinations request");
+		let response = self.send("WalkupScanToCompDestinations", self.client.delete(url))
+			.map_err(|e| {
+				log::error!("Error sending DELETE WalkupScanToCompDestinations request: {}", e);
+				DeleteDestinationError
+			})?;
 
 		match response.status() {
 			StatusCode::OK => {
-				self.active_destinations.iter()
-					.position(|id| *id == uuid)
-					.expect("Could not find uuid in active destinations");
+				self.active_destinations.retain(|id| *id != uuid);
 				log::info!("Deletion of destination {} successful", uuid);
 				Ok(())
 			},
@@ -199,17 +263,16 @@ impl<'a> HpApi {
 			request = request.header("If-None-Match", etag);
 		}
 
-		let response = request
-			.send()
-			.expect("Error sending download page request");
+		let response = self.send("EventTable", request)
+			.map_err(|e| ApiError::new(&format!("Error requesting EventTable: {}", e)))?;
 
 		match response.status() {
 			StatusCode::OK => {
 				let text = response
 					.text()
-					.expect("Error reading text from response");
+					.map_err(|e| ApiError::new(&format!("Error reading EventTable: {}", e)))?;
 
-				let table: EventTable = from_str(&text).unwrap();
+				let table: EventTable = from_str(&text).map_err(|e| ApiError::new(&e))?;
 				if let Some(event) = table.events.last() {
 					log::debug!("Setting last known etag to {}", event.aging_stamp);
 					self.last_known_etag = Some(event.aging_stamp.clone())
@@ -237,17 +300,16 @@ impl<'a> HpApi {
 			request = request.header("If-None-Match", etag);
 		}
 
-		let response = request
-			.send()
-			.expect("Error sending download page request");
+		let response = self.send("EventTable", request)
+			.map_err(|e| ApiError::new(&format!("Error requesting EventTable: {}", e)))?;
 
 		match response.status() {
 			StatusCode::OK => {
 				let text = response
 					.text()
-					.expect("Error reading text from response");
+					.map_err(|e| ApiError::new(&format!("Error reading EventTable: {}", e)))?;
 
-				let table: EventTable = from_str(&text).unwrap();
+				let table: EventTable = from_str(&text).map_err(|e| ApiError::new(&e))?;
 				if let Some(event) = table.events.last() {
 					log::debug!("Setting last known etag to {}", event.aging_stamp);
 					self.last_known_etag = Some(event.aging_stamp.clone())
@@ -271,20 +333,19 @@ impl<'a> HpApi {
 
 		log::debug!("Post body: {}", str);
 
-		let response = self.client.post(url)
+		let request = self.client.post(url)
 			.header("Content-Type", "text/xml")
-			.body(str)
-			.send()
-			.expect("Error sending POST Scan/Jobs request");
+			.body(str);
+		let response = self.send("Scan/Jobs", request)
+			.map_err(|e| ApiError::new(&format!("Error creating scan job: {}", e)))?;
 
 		match response.status() {
 			StatusCode::CREATED => {
 				let location = response
 					.headers()
 					.get("Location")
-					.expect("Missing Location header in response")
-					.to_str()
-					.expect("Could not map Location header to string");
+					.and_then(|location| location.to_str().ok())
+					.ok_or_else(|| ApiError::new("Missing Location header in response"))?;
 
 				log::debug!("Successfully created new Scan Job with url {}", location);
 				Ok(location.to_string())
@@ -299,19 +360,18 @@ impl<'a> HpApi {
 	pub fn get_job_with_url(&'a self, url: &String) -> Result<Job, ApiError> {
 		log::debug!("Getting job with url");
 
-		let response = self.client.get(url)
-			.send()
-			.expect("Error sending download page request");
+		let response = self.send("Scan/Jobs", self.client.get(url))
+			.map_err(|e| ApiError::new(&format!("Error requesting scan job: {}", e)))?;
 
 		match response.status() {
 			StatusCode::OK => {
 				let text = response
 					.text()
-					.expect("Error reading text from response");
-				Ok(from_str(&text).unwrap())
+					.map_err(|e| ApiError::new(&format!("Error reading scan job: {}", e)))?;
+				from_str(&text).map_err(|e| ApiError::new(&e))
 			}
-			_ => {
-				Err(ApiError::new("Error reading WalkupScanToCompEvent"))
+			status => {
+				Err(ApiError::new(&format!("Error reading scan job: {}", status)))
 			}
 		}
 	}
@@ -320,16 +380,15 @@ impl<'a> HpApi {
 		let url = self.base_url.join("/WalkupScanToComp/WalkupScanToCompEvent")
 			.expect("Error generating URL");
 
-		let response = self.client.get(url)
-			.send()
-			.expect("Error sending download page request");
+		let response = self.send("WalkupScanToCompEvent", self.client.get(url))
+			.map_err(|e| ApiError::new(&format!("Error requesting WalkupScanToCompEvent: {}", e)))?;
 
 		match response.status() {
 			StatusCode::OK => {
 				let text = response
 					.text()
-					.expect("Error reading text from response");
-				Ok(from_str(&text).unwrap())
+					.map_err(|e| ApiError::new(&format!("Error reading WalkupScanToCompEvent: {}", e)))?;
+				from_str(&text).map_err(|e| ApiError::new(&e))
 			}
 			_ => {
 				Err(ApiError::new("Error reading WalkupScanToCompEvent"))
@@ -337,44 +396,85 @@ impl<'a> HpApi {
 		}
 	}
 
+	pub fn get_product_config(&'a self) -> Result<ProductConfig, ApiError> {
+		let url = self.base_url.join("/DevMgmt/ProductConfigDyn.xml")
+			.expect("Error generating URL");
+
+		let response = self.send("ProductConfigDyn", self.client.get(url))
+			.map_err(|e| ApiError::new(&format!("Error sending ProductConfigDyn request: {}", e)))?;
+
+		match response.status() {
+			StatusCode::OK => {
+				let text = response
+					.text()
+					.map_err(|e| ApiError::new(&format!("Error reading ProductConfigDyn: {}", e)))?;
+				from_str(&text).map_err(|e| ApiError::new(&e))
+			}
+			_ => {
+				Err(ApiError::new("Error reading ProductConfigDyn"))
+			}
+		}
+	}
+
 	pub fn get_scanner_status(&'a self) -> Result<ScanStatus, ApiError> {
 		let url = self.base_url.join("/Scan/Status")
 			.expect("Error generating URL");
 
-		let response = self.client.get(url)
-			.send()
-			.expect("Error sending download page request");
+		let response = self.send("Scan/Status", self.client.get(url))
+			.map_err(|e| ApiError::new(&format!("Error requesting scanner status: {}", e)))?;
 
 		match response.status() {
 			StatusCode::OK => {
 				let text = response
 					.text()
-					.expect("Error reading text from response");
-				Ok(from_str(&text).unwrap())
+					.map_err(|e| ApiError::new(&format!("Error reading scanner status: {}", e)))?;
+				from_str(&text).map_err(|e| ApiError::new(&e))
 			}
-			_ => {
-				Err(ApiError::new("Error reading WalkupScanToCompEvent"))
+			status => {
+				Err(ApiError::new(&format!("Error reading scanner status: {}", status)))
 			}
 		}
 	}
 
-	pub fn download_page(&'a self, path: &String) -> Result<File, DownloadError> {
-		let url = self.base_url.join(&path)
+	pub fn get_scan_caps(&'a self) -> Result<ScanCaps, ApiError> {
+		let url = self.base_url.join("/Scan/ScanCaps")
 			.expect("Error generating URL");
-		let response = self.client.get(url)
-			.send()
-			.expect("Error sending download page request");
+
+		let response = self.send("Scan/ScanCaps", self.client.get(url))
+			.map_err(|e| ApiError::new(&format!("Error requesting scanner capabilities: {}", e)))?;
 
 		match response.status() {
 			StatusCode::OK => {
-				let mut file = File::create("./file.pdf")
-					.expect("could not creat file");
+				let text = response
+					.text()
+					.map_err(|e| ApiError::new(&format!("Error reading scanner capabilities: {}", e)))?;
+				from_str(&text).map_err(|e| ApiError::new(&e))
+			}
+			status => {
+				Err(ApiError::new(&format!("Error reading scanner capabilities: {}", status)))
+			}
+		}
+	}
+
+	pub fn download_page(&'a self, path: &str) -> Result<Vec<u8>, DownloadError> {
+		let url = self.base_url.join(path)
+			.expect("Error generating URL");
+		let response = self.send("Scan/Jobs/Pages", self.client.get(url))
+			.map_err(|e| {
+				log::error!("Error sending download page request: {}", e);
+				DownloadError
+			})?;
 
-				let mut content =  Cursor::new(response.bytes()
-					.expect("Could not turn response into bytes"));
-				let _ = copy(&mut content, &mut file);
+		match response.status() {
+			StatusCode::OK => {
+				let content = response.bytes()
+					.map_err(|e| {
+						log::error!("Error downloading page: {}", e);
+						DownloadError
+					})?;
+				METRICS.bytes_downloaded.with_label_values(&[&self.name]).inc_by(content.len() as u64);
 				log::debug!("Download Successful");
-				Ok(file)
+				Ok(content.to_vec())
 			},
 			_ => {
 				log::error!("Error downloading page");
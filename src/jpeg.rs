@@ -0,0 +1,85 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JpegInfo {
+	pub width: u16,
+	pub height: u16,
+	pub components: u8,
+}
+
+/// Returns the position of the start of frame segment of a baseline or
+/// progressive JPEG.
+fn find_sof(data: &[u8]) -> Option<usize> {
+	let mut pos = 2;
+	while pos + 4 <= data.len() {
+		if data[pos] != 0xFF {
+			return None;
+		}
+		let marker = data[pos + 1];
+		// padding bytes between segments
+		if marker == 0xFF {
+			pos += 1;
+			continue;
+		}
+		if (0xC0..=0xC2).contains(&marker) {
+			return Some(pos);
+		}
+		let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+		pos += 2 + length;
+	}
+	None
+}
+
+pub fn jpeg_info(data: &[u8]) -> Option<JpegInfo> {
+	if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+		return None;
+	}
+	let sof = find_sof(data)?;
+	if sof + 10 > data.len() {
+		return None;
+	}
+	Some(JpegInfo {
+		height: u16::from_be_bytes([data[sof + 5], data[sof + 6]]),
+		width: u16::from_be_bytes([data[sof + 7], data[sof + 8]]),
+		components: data[sof + 9],
+	})
+}
+
+/// The scanner streams ADF pages before it knows their length, so the
+/// height written to the JPEG header can be wrong. The real number of lines
+/// is only reported in the `PostScanPage` after the upload.
+pub fn set_jpeg_height(data: &mut [u8], height: u16) {
+	if let Some(sof) = find_sof(data) {
+		if sof + 7 <= data.len() {
+			data[sof + 5..sof + 7].copy_from_slice(&height.to_be_bytes());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use image::{DynamicImage, GrayImage, RgbImage};
+	use crate::processing::encode_jpeg;
+	use super::*;
+
+	#[test]
+	fn reads_dimensions() {
+		let rgb = encode_jpeg(&DynamicImage::ImageRgb8(RgbImage::new(40, 30)), 90).unwrap();
+		assert_eq!(jpeg_info(&rgb), Some(JpegInfo { width: 40, height: 30, components: 3 }));
+		let gray = encode_jpeg(&DynamicImage::ImageLuma8(GrayImage::new(8, 16)), 90).unwrap();
+		assert_eq!(jpeg_info(&gray), Some(JpegInfo { width: 8, height: 16, components: 1 }));
+	}
+
+	#[test]
+	fn rejects_other_data() {
+		assert_eq!(jpeg_info(b""), None);
+		assert_eq!(jpeg_info(b"%PDF-1.4"), None);
+		let jpeg = encode_jpeg(&DynamicImage::ImageLuma8(GrayImage::new(8, 8)), 90).unwrap();
+		assert_eq!(jpeg_info(&jpeg[..20]), None);
+	}
+
+	#[test]
+	fn corrects_height() {
+		let mut jpeg = encode_jpeg(&DynamicImage::ImageLuma8(GrayImage::new(8, 8)), 90).unwrap();
+		set_jpeg_height(&mut jpeg, 1234);
+		assert_eq!(jpeg_info(&jpeg).unwrap().height, 1234);
+	}
+}
@@ -0,0 +1,65 @@
+use std::{env, io};
+use std::io::IsTerminal;
+use clap::ValueEnum;
+use tracing_subscriber::EnvFilter;
+
+/// Parts of environment variable names whose values are never logged.
+const SECRET_MARKERS: [&str; 5] = ["KEY", "TOKEN", "SECRET", "PASSWORD", "CREDENTIAL"];
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum LogFormat {
+	#[default]
+	Text,
+	/// One JSON object per line including the fields of the current scan
+	Json,
+}
+
+/// Installs the subscriber for spans and events. Records of the `log`
+/// macros are forwarded to it and carry the fields of the current span.
+pub fn init(format: LogFormat) {
+	let filter = EnvFilter::try_from_default_env()
+		.unwrap_or_else(|_| EnvFilter::new("info"));
+	// stdout is kept free for the output of subcommands
+	let builder = tracing_subscriber::fmt()
+		.with_env_filter(filter)
+		.with_ansi(io::stderr().is_terminal())
+		.with_writer(io::stderr);
+
+	match format {
+		LogFormat::Text => builder.init(),
+		LogFormat::Json => builder.json()
+			.with_current_span(true)
+			.with_span_list(true)
+			.init(),
+	}
+}
+
+/// Variables ending in `_FILE` name the file of a secret and are logged.
+pub fn is_secret(name: &str) -> bool {
+	let name = name.to_uppercase();
+	!name.ends_with("_FILE") && SECRET_MARKERS.iter().any(|marker| name.contains(marker))
+}
+
+/// Logs the environment at debug level with the values of secrets replaced.
+pub fn log_environment() {
+	log::debug!("Environment variables:");
+	for (key, value) in env::vars() {
+		let value = if is_secret(&key) { "<redacted>" } else { value.as_str() };
+		log::debug!("{}={}", key, value);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn detects_secrets() {
+		for name in ["SENDGRID_API_KEY", "PAPERLESS_TOKEN", "HTTP_PASSWORD", "aws_secret_access_key", "GOOGLE_APPLICATION_CREDENTIALS"] {
+			assert!(is_secret(name), "{}", name);
+		}
+		for name in ["HTTP_PASSWORD_FILE", "PRINTER_URL", "MAIL_TO", "HOME"] {
+			assert!(!is_secret(name), "{}", name);
+		}
+	}
+}
@@ -0,0 +1,80 @@
+use std::sync::LazyLock;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+
+pub struct Metrics {
+	registry: Registry,
+	pub scans_started: IntCounterVec,
+	pub scans_completed: IntCounterVec,
+	pub scans_failed: IntCounterVec,
+	pub pages_scanned: IntCounterVec,
+	pub bytes_downloaded: IntCounterVec,
+	pub deliveries: IntCounterVec,
+	pub api_requests: HistogramVec,
+	pub printer_reachable: IntGaugeVec,
+	pub destinations_registered: IntGaugeVec,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+	let counter = IntCounterVec::new(Opts::new(name, help), labels)
+		.expect("Invalid metric");
+	registry.register(Box::new(counter.clone()))
+		.expect("Metric registered twice");
+	counter
+}
+
+impl Metrics {
+	fn new() -> Metrics {
+		let registry = Registry::new_custom(Some("hp_scan_to".to_string()), None)
+			.expect("Invalid metrics prefix");
+
+		// time until the response headers arrive, downloading the body is not
+		// included. Long polls of the event table are answered with the first
+		// event and the client gives up after 3 minutes
+		let api_requests = HistogramVec::new(
+			HistogramOpts::new("api_request_duration_seconds", "Duration of requests to the printer")
+				.buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 180.0]),
+			&["printer", "endpoint", "status"])
+			.expect("Invalid metric");
+		let printer_reachable = IntGaugeVec::new(Opts::new("printer_reachable", "Whether the last request reached the printer"), &["printer"])
+			.expect("Invalid metric");
+		let destinations_registered = IntGaugeVec::new(Opts::new("destinations_registered", "Walk-up destinations registered on the printer"), &["printer"])
+			.expect("Invalid metric");
+
+		for collector in [
+			Box::new(api_requests.clone()) as Box<dyn prometheus::core::Collector>,
+			Box::new(printer_reachable.clone()),
+			Box::new(destinations_registered.clone()),
+		] {
+			registry.register(collector).expect("Metric registered twice");
+		}
+
+		Metrics {
+			scans_started: counter(&registry, "scans_started_total", "Scan jobs started", &["printer", "destination"]),
+			scans_completed: counter(&registry, "scans_completed_total", "Scan jobs completed", &["printer", "destination"]),
+			scans_failed: counter(&registry, "scans_failed_total", "Scan jobs that failed", &["printer", "destination"]),
+			pages_scanned: counter(&registry, "pages_scanned_total", "Pages downloaded from the printer", &["printer", "destination"]),
+			bytes_downloaded: counter(&registry, "bytes_downloaded_total", "Bytes of scanned pages downloaded from the printer", &["printer"]),
+			deliveries: counter(&registry, "deliveries_total", "Delivery attempts by sink and result", &["sink", "result"]),
+			api_requests,
+			printer_reachable,
+			destinations_registered,
+			registry,
+		}
+	}
+
+	pub fn delivery(&self, sink: &str, success: bool) {
+		let result = if success { "success" } else { "failure" };
+		self.deliveries.with_label_values(&[sink, result]).inc();
+	}
+
+	/// Renders all metrics in the Prometheus text format.
+	pub fn render(&self) -> String {
+		let mut buffer = Vec::new();
+		if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+			log::error!("Error encoding metrics: {}", e);
+		}
+		String::from_utf8(buffer).unwrap_or_default()
+	}
+}
@@ -1,3 +1,6 @@
+// the yaserde derive macros generate their impls inside of a const block
+#![allow(non_local_definitions, clippy::explicit_auto_deref)]
+
 use std::fmt;
 use yaserde_derive::*;
 
@@ -146,6 +149,114 @@ pub struct WalkupScanToCompEvent {
 	pub event_type: String,
 }
 
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19",
+rename = "ScanCaps"
+)]
+pub struct ScanCaps {
+	#[yaserde(rename = "ColorEntries")]
+	pub color_entries: ColorEntries,
+	#[yaserde(rename = "Platen")]
+	pub platen: Option<PlatenCaps>,
+	#[yaserde(rename = "Adf")]
+	pub adf: Option<AdfCaps>,
+}
+
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19",
+rename = "ColorEntries"
+)]
+pub struct ColorEntries {
+	#[yaserde(rename = "ColorEntry")]
+	pub entries: Vec<ColorEntry>,
+}
+
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19",
+rename = "ColorEntry"
+)]
+pub struct ColorEntry {
+	#[yaserde(rename = "ColorType")]
+	pub color_type: String,
+}
+
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19",
+rename = "Platen"
+)]
+pub struct PlatenCaps {
+	#[yaserde(rename = "InputSourceCaps")]
+	pub input_source_caps: InputSourceCaps,
+}
+
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19",
+rename = "Adf"
+)]
+pub struct AdfCaps {
+	#[yaserde(rename = "AdfOptions")]
+	pub options: AdfOptions,
+	#[yaserde(rename = "InputSourceCaps")]
+	pub input_source_caps: InputSourceCaps,
+	#[yaserde(rename = "FeederCapacity")]
+	pub feeder_capacity: Option<i32>,
+}
+
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19",
+rename = "AdfOptions"
+)]
+pub struct AdfOptions {
+	#[yaserde(rename = "AdfOption")]
+	pub options: Vec<String>,
+}
+
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19",
+rename = "InputSourceCaps"
+)]
+pub struct InputSourceCaps {
+	#[yaserde(rename = "MinWidth")]
+	pub min_width: i32,
+	#[yaserde(rename = "MinHeight")]
+	pub min_height: i32,
+	#[yaserde(rename = "MaxWidth")]
+	pub max_width: i32,
+	#[yaserde(rename = "MaxHeight")]
+	pub max_height: i32,
+	#[yaserde(rename = "Resolutions")]
+	pub resolutions: Resolutions,
+}
+
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19",
+rename = "Resolutions"
+)]
+pub struct Resolutions {
+	#[yaserde(rename = "Resolution")]
+	pub resolutions: Vec<Resolution>,
+}
+
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19",
+rename = "Resolution"
+)]
+pub struct Resolution {
+	#[yaserde(rename = "XResolution")]
+	pub x_resolution: i32,
+	#[yaserde(rename = "YResolution")]
+	pub y_resolution: i32,
+}
+
 #[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
 #[yaserde(
 namespace = "ev: http://www.hp.com/schemas/imaging/con/ledm/events/2007/09/16",
@@ -248,6 +359,41 @@ pub struct PostScanPage {
 	pub total_lines: i32,
 }
 
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "prdcfgdyn2: http://www.hp.com/schemas/imaging/con/ledm/productconfigdyn/2009/03/16",
+namespace = "prdcfgdyn: http://www.hp.com/schemas/imaging/con/ledm/productconfigdyn/2007/11/05",
+namespace = "dd: http://www.hp.com/schemas/imaging/con/dictionaries/1.0/",
+prefix = "prdcfgdyn2"
+rename = "ProductConfigDyn"
+)]
+pub struct ProductConfig {
+	#[yaserde(prefix = "prdcfgdyn", rename = "ProductInformation")]
+	pub product_information: ProductInformation,
+}
+
+#[derive(YaDeserialize, YaSerialize, Default, Debug, PartialEq, Clone)]
+#[yaserde(
+namespace = "prdcfgdyn: http://www.hp.com/schemas/imaging/con/ledm/productconfigdyn/2007/11/05",
+namespace = "dd: http://www.hp.com/schemas/imaging/con/dictionaries/1.0/",
+prefix = "prdcfgdyn"
+rename = "ProductInformation"
+)]
+pub struct ProductInformation {
+	#[yaserde(prefix = "dd", rename = "MakeAndModel")]
+	pub make_and_model: String,
+	#[yaserde(prefix = "dd", rename = "SerialNumber")]
+	pub serial_number: String,
+}
+
+/// Identifies the printer a document was scanned on.
+#[derive(Debug, Clone, Default)]
+pub struct PrinterInfo {
+	pub host: String,
+	pub model: String,
+	pub serial: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct GetDestinationError;
 
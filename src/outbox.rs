@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::config::{DestinationConfig, OutboxConfig};
+use crate::history::History;
+use crate::metrics::METRICS;
+use crate::processing::Document;
+use crate::sinks::{select_sinks, SinkConfig, SinkError};
+
+const DOCUMENT_FILE: &str = "document.pdf";
+const ENTRY_FILE: &str = "entry.json";
+
+/// A document waiting for delivery. Sinks are referenced by their name and
+/// position in the destination configuration so no credentials end up on
+/// disk.
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+	document: Document,
+	/// Delivered to the fallback sinks of the destination
+	fallback: bool,
+	targets: Vec<Target>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Target {
+	/// Only tells apart sinks with the same name, the configuration may have
+	/// been reordered since
+	sink: usize,
+	name: String,
+	attempts: u32,
+	/// Unix timestamp of the next delivery attempt
+	next_attempt: i64,
+	delivered: bool,
+	last_error: Option<String>,
+}
+
+/// Documents are written to `pending` before they are delivered and only
+/// removed once every sink accepted them. A background thread retries
+/// failed deliveries with exponential backoff, also after a restart.
+pub struct Outbox {
+	config: OutboxConfig,
+	destinations: HashMap<String, DestinationConfig>,
+	wakeup: (Mutex<bool>, Condvar),
+	counter: AtomicU32,
+	history: Arc<History>,
+}
+
+impl Outbox {
+	/// Creates the outbox directories and starts delivering pending documents.
+	pub fn start(config: OutboxConfig, destinations: &[DestinationConfig], history: Arc<History>) -> Result<Arc<Outbox>, OutboxError> {
+		let outbox = Outbox {
+			destinations: destinations.iter()
+				.map(|destination| (destination.name.clone(), destination.clone()))
+				.collect(),
+			config,
+			wakeup: (Mutex::new(false), Condvar::new()),
+			counter: AtomicU32::new(0),
+			history,
+		};
+
+		for directory in [outbox.pending(), outbox.dead_letter()] {
+			fs::create_dir_all(&directory)
+				.map_err(|e| OutboxError::new(&format!("Error creating {}: {}", directory.display(), e)))?;
+		}
+
+		let outbox = Arc::new(outbox);
+		let worker = Arc::clone(&outbox);
+		thread::spawn(move || {
+			log::debug!("Spawned new thread delivering the outbox");
+			worker.run();
+		});
+
+		Ok(outbox)
+	}
+
+	fn pending(&self) -> PathBuf {
+		Path::new(&self.config.directory).join("pending")
+	}
+
+	fn dead_letter(&self) -> PathBuf {
+		Path::new(&self.config.directory).join("dead-letter")
+	}
+
+	/// Whether documents of the destination can be queued.
+	pub fn delivers_to(&self, destination: &str) -> bool {
+		self.destinations.contains_key(destination)
+	}
+
+	/// Stores the document for delivery to the sinks of its destination.
+	pub fn enqueue(&self, document: &Document) -> Result<(), OutboxError> {
+		let destination = self.destinations.get(&document.destination)
+			.ok_or_else(|| OutboxError::new(&format!("Unknown destination {}", document.destination)))?;
+		let (fallback, sinks) = select_sinks(document, destination);
+		let now = Utc::now().timestamp();
+
+		let entry = Entry {
+			document: document.clone(),
+			fallback,
+			targets: sinks.iter().enumerate()
+				.map(|(i, sink)| Target {
+					sink: i,
+					name: sink.name(),
+					attempts: 0,
+					next_attempt: now,
+					delivered: false,
+					last_error: None,
+				})
+				.collect(),
+		};
+
+		// names sort in the order the documents were scanned
+		let id = format!("{}-{}-{:04}", Utc::now().format("%Y%m%dT%H%M%S%.3f"), std::process::id(),
+			self.counter.fetch_add(1, Ordering::Relaxed));
+		let directory = self.pending().join(id);
+		let error = |e: std::io::Error| OutboxError::new(&format!("Error writing {}: {}", directory.display(), e));
+
+		fs::create_dir_all(&directory).map_err(error)?;
+		fs::write(directory.join(DOCUMENT_FILE), &document.content).map_err(error)?;
+		// the entry file is written last, directories without it are incomplete
+		write_entry(&directory, &entry).map_err(error)?;
+		log::info!("Queued {} for delivery to {} sinks", document.filename, entry.targets.len());
+
+		let (lock, condvar) = &self.wakeup;
+		*lock.lock().unwrap() = true;
+		condvar.notify_one();
+		Ok(())
+	}
+
+	fn run(&self) {
+		loop {
+			let next = self.deliver_pending();
+			let timeout = match next {
+				Some(next) => Duration::from_secs((next - Utc::now().timestamp()).max(0) as u64),
+				None => Duration::from_secs(self.config.max_retry_delay_secs.max(60)),
+			};
+
+			let (lock, condvar) = &self.wakeup;
+			let mut queued = lock.lock().unwrap();
+			if !*queued {
+				queued = condvar.wait_timeout(queued, timeout).unwrap().0;
+			}
+			*queued = false;
+		}
+	}
+
+	/// Attempts all due deliveries and returns when the next one is due.
+	fn deliver_pending(&self) -> Option<i64> {
+		let mut directories = match fs::read_dir(self.pending()) {
+			Ok(entries) => entries
+				.filter_map(|entry| entry.ok())
+				.map(|entry| entry.path())
+				.filter(|path| path.join(ENTRY_FILE).exists())
+				.collect::<Vec<PathBuf>>(),
+			Err(e) => {
+				log::error!("Error reading outbox: {}", e);
+				return None
+			}
+		};
+		directories.sort();
+
+		directories.iter()
+			.filter_map(|directory| self.deliver_entry(directory))
+			.min()
+	}
+
+	fn deliver_entry(&self, directory: &Path) -> Option<i64> {
+		let mut entry = match read_entry(directory) {
+			Ok(entry) => entry,
+			Err(e) => {
+				log::error!("Error reading outbox entry {}: {}", directory.display(), e);
+				self.move_to_dead_letter(directory);
+				return None
+			}
+		};
+
+		// same scan id as the lines logged while scanning
+		let span = tracing::info_span!("delivery", destination = %entry.document.destination,
+			document = %entry.document.filename, scan_id = tracing::field::Empty);
+		if let Some(scan_id) = entry.document.scan_id {
+			span.record("scan_id", scan_id);
+		}
+		let _entered = span.enter();
+
+		let destination = match self.destinations.get(&entry.document.destination) {
+			Some(destination) => destination,
+			None => {
+				log::error!("Destination {} of {} is no longer configured", entry.document.destination, entry.document.filename);
+				self.move_to_dead_letter(directory);
+				return None
+			}
+		};
+		let sinks = match entry.fallback {
+			true => &destination.fallback_sink,
+			false => &destination.sink,
+		};
+
+		// a document must never end up at another sink after a configuration change
+		if let Some(target) = entry.targets.iter()
+			.find(|target| self.is_pending(target) && find_sink(sinks, target).is_none()) {
+			log::error!("Sink {} of {} is no longer configured", target.name, entry.document.filename);
+			self.move_to_dead_letter(directory);
+			return None
+		}
+
+		let now = Utc::now().timestamp();
+		let due = entry.targets.iter()
+			.any(|target| self.is_pending(target) && target.next_attempt <= now);
+
+		if due {
+			match fs::read(directory.join(DOCUMENT_FILE)) {
+				Ok(content) => entry.document.content = content,
+				Err(e) => {
+					log::error!("Error reading {}: {}", entry.document.filename, e);
+					self.move_to_dead_letter(directory);
+					return None
+				}
+			}
+
+			for target in entry.targets.iter_mut()
+				.filter(|target| target.next_attempt <= now)
+				.filter(|target| self.is_pending(target)) {
+				target.attempts += 1;
+				let result = match find_sink(sinks, target) {
+					Some(sink_config) => sink_config.build().deliver(&entry.document),
+					None => Err(SinkError::new("Sink is no longer configured")),
+				};
+
+				METRICS.delivery(&target.name, result.is_ok());
+				self.history.record_delivery(entry.document.scan_id, &entry.document.filename, &target.name, &result);
+				match result {
+					Ok(_) => {
+						log::info!("Delivered {} to {}", entry.document.filename, target.name);
+						target.delivered = true;
+						target.last_error = None;
+					}
+					Err(e) => {
+						let delay = self.retry_delay(target.attempts);
+						log::error!("Error delivering {} to {} (attempt {} of {}): {}",
+							entry.document.filename, target.name, target.attempts, self.config.max_attempts, e);
+						target.next_attempt = Utc::now().timestamp() + delay as i64;
+						target.last_error = Some(e.to_string());
+					}
+				}
+			}
+
+			if let Err(e) = write_entry(directory, &entry) {
+				log::error!("Error updating outbox entry {}: {}", directory.display(), e);
+			}
+		}
+
+		if entry.targets.iter().all(|target| target.delivered) {
+			if let Err(e) = fs::remove_dir_all(directory) {
+				log::error!("Error removing {} from outbox: {}", directory.display(), e);
+			}
+			return None
+		}
+
+		let next = entry.targets.iter()
+			.filter(|target| self.is_pending(target))
+			.map(|target| target.next_attempt)
+			.min();
+
+		if next.is_none() {
+			log::error!("Giving up delivering {} after {} attempts", entry.document.filename, self.config.max_attempts);
+			self.move_to_dead_letter(directory);
+		}
+		next
+	}
+
+	fn is_pending(&self, target: &Target) -> bool {
+		!target.delivered && target.attempts < self.config.max_attempts
+	}
+
+	fn retry_delay(&self, attempts: u32) -> u64 {
+		let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+		self.config.retry_delay_secs.saturating_mul(factor)
+			.min(self.config.max_retry_delay_secs)
+	}
+
+	fn move_to_dead_letter(&self, directory: &Path) {
+		let Some(name) = directory.file_name() else { return };
+		let target = self.dead_letter().join(name);
+		match fs::rename(directory, &target) {
+			Ok(_) => log::warn!("Moved {} to {}", directory.display(), target.display()),
+			Err(e) => log::error!("Error moving {} to dead letter folder: {}", directory.display(), e),
+		}
+	}
+}
+
+/// The sink with the name of the target, preferring the one at its position.
+fn find_sink<'a>(sinks: &'a [SinkConfig], target: &Target) -> Option<&'a SinkConfig> {
+	let named = |sink: &&SinkConfig| sink.name() == target.name;
+	sinks.get(target.sink).filter(named)
+		.or_else(|| sinks.iter().find(named))
+}
+
+fn read_entry(directory: &Path) -> Result<Entry, OutboxError> {
+	let content = fs::read_to_string(directory.join(ENTRY_FILE))
+		.map_err(|e| OutboxError::new(&e.to_string()))?;
+	serde_json::from_str(&content)
+		.map_err(|e| OutboxError::new(&e.to_string()))
+}
+
+/// Replaces the entry file atomically so a crash never leaves it truncated.
+fn write_entry(directory: &Path, entry: &Entry) -> std::io::Result<()> {
+	let content = serde_json::to_vec_pretty(entry)?;
+	let tmp = directory.join(format!("{}.tmp", ENTRY_FILE));
+	fs::write(&tmp, content)?;
+	fs::rename(tmp, directory.join(ENTRY_FILE))
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxError {
+	pub details: String,
+}
+
+impl OutboxError {
+	pub fn new(msg: &str) -> OutboxError {
+		OutboxError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for OutboxError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.details)
+	}
+}
+
+impl std::error::Error for OutboxError {}
+
+#[cfg(test)]
+mod tests {
+	use serde::Deserialize;
+	use super::*;
+
+	#[derive(Deserialize)]
+	struct Sinks {
+		sink: Vec<SinkConfig>,
+	}
+
+	fn sinks(urls: &[&str]) -> Vec<SinkConfig> {
+		let config = urls.iter()
+			.map(|url| format!("[[sink]]\ntype = \"webhook\"\nurl = \"{}\"\n", url))
+			.collect::<String>();
+		toml::from_str::<Sinks>(&config).unwrap().sink
+	}
+
+	fn target(sink: usize, name: &str) -> Target {
+		Target { sink, name: name.to_string(), attempts: 0, next_attempt: 0, delivered: false, last_error: None }
+	}
+
+	#[test]
+	fn finds_moved_sink_by_name() {
+		let sinks = sinks(&["http://b", "http://a"]);
+		let found = find_sink(&sinks, &target(0, "Webhook http://a")).unwrap();
+		assert_eq!(found.name(), "Webhook http://a");
+	}
+
+	#[test]
+	fn prefers_position_of_equally_named_sinks() {
+		let sinks = sinks(&["http://a", "http://a"]);
+		let found = find_sink(&sinks, &target(1, "Webhook http://a")).unwrap();
+		assert!(std::ptr::eq(found, &sinks[1]));
+	}
+
+	#[test]
+	fn removed_sink_is_not_replaced() {
+		let sinks = sinks(&["http://b"]);
+		assert!(find_sink(&sinks, &target(0, "Webhook http://a")).is_none());
+	}
+}
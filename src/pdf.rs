@@ -0,0 +1,312 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use chrono::{DateTime, Local};
+use crate::jpeg::jpeg_info;
+use crate::processing::ProcessingError;
+
+/// A recognized word. Coordinates are pixels of the page image with the
+/// origin in the top left corner.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+	pub text: String,
+	pub left: u32,
+	pub top: u32,
+	pub width: u32,
+	pub height: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct PdfPage {
+	pub image: Vec<u8>,
+	pub resolution: u16,
+	pub words: Vec<Word>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PdfMetadata {
+	pub title: Option<String>,
+	pub author: Option<String>,
+	pub subject: Option<String>,
+	pub keywords: Option<String>,
+}
+
+pub struct PdfOptions {
+	pub metadata: PdfMetadata,
+	pub created: DateTime<Local>,
+	/// ICC profile for the output intent. Setting it produces a PDF/A-2b file.
+	pub pdfa_icc_profile: Option<Vec<u8>>,
+}
+
+const PRODUCER: &str = concat!("rust-hp ", env!("CARGO_PKG_VERSION"));
+
+struct PdfWriter {
+	buffer: Vec<u8>,
+	offsets: Vec<usize>,
+}
+
+impl PdfWriter {
+	fn new(version: &str) -> PdfWriter {
+		let mut buffer = format!("%PDF-{}\n", version).into_bytes();
+		// binary comment so the file is treated as binary
+		buffer.extend_from_slice(b"%\xE2\xE3\xCF\xD3\n");
+		PdfWriter {
+			buffer,
+			offsets: Vec::new(),
+		}
+	}
+
+	fn add_object(&mut self, id: usize, body: &[u8]) {
+		if self.offsets.len() < id {
+			self.offsets.resize(id, 0);
+		}
+		self.offsets[id - 1] = self.buffer.len();
+		let _ = writeln!(self.buffer, "{} 0 obj", id);
+		self.buffer.extend_from_slice(body);
+		self.buffer.extend_from_slice(b"\nendobj\n");
+	}
+
+	fn add_stream(&mut self, id: usize, dict: &str, data: &[u8]) {
+		let mut body = format!("<< {} /Length {} >>\nstream\n", dict, data.len()).into_bytes();
+		body.extend_from_slice(data);
+		body.extend_from_slice(b"\nendstream");
+		self.add_object(id, &body);
+	}
+
+	fn finish(mut self, root: usize, info: usize) -> Vec<u8> {
+		let mut hasher = DefaultHasher::new();
+		self.buffer.hash(&mut hasher);
+		let hash = hasher.finish();
+		hash.rotate_left(32).hash(&mut hasher);
+		let id = format!("{:016x}{:016x}", hash, hasher.finish());
+
+		let xref = self.buffer.len();
+		let _ = write!(self.buffer, "xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+		for offset in &self.offsets {
+			let _ = writeln!(self.buffer, "{:010} 00000 n ", offset);
+		}
+		let _ = write!(self.buffer, "trailer\n<< /Size {} /Root {} 0 R /Info {} 0 R /ID [<{}> <{}>] >>\nstartxref\n{}\n%%EOF\n",
+			self.offsets.len() + 1, root, info, id, id, xref);
+		self.buffer
+	}
+}
+
+/// Encodes text as a PDF literal string using WinAnsiEncoding. Characters
+/// outside of Latin-1 cannot be represented and are replaced.
+fn encode_text(text: &str) -> Vec<u8> {
+	let mut encoded = vec![b'('];
+	for c in text.chars() {
+		match c {
+			'(' | ')' | '\\' => {
+				encoded.push(b'\\');
+				encoded.push(c as u8);
+			}
+			' '..='~' | '\u{A0}'..='\u{FF}' => encoded.push(c as u32 as u8),
+			_ => encoded.push(b'?'),
+		}
+	}
+	encoded.push(b')');
+	encoded
+}
+
+/// Encodes text as a PDF string in UTF-16BE, which is allowed for text
+/// outside of content streams.
+fn encode_text_string(text: &str) -> String {
+	let mut encoded = String::from("<FEFF");
+	for unit in text.encode_utf16() {
+		encoded.push_str(&format!("{:04X}", unit));
+	}
+	encoded.push('>');
+	encoded
+}
+
+fn escape_xml(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
+
+/// Formats a date as required by the document information dictionary, for
+/// example `D:20240131120000+01'00'`.
+fn pdf_date(date: &DateTime<Local>) -> String {
+	let offset = date.offset().local_minus_utc();
+	let sign = if offset < 0 { '-' } else { '+' };
+	let offset = offset.abs();
+	format!("D:{}{}{:02}'{:02}'", date.format("%Y%m%d%H%M%S"), sign, offset / 3600, offset % 3600 / 60)
+}
+
+fn info_dictionary(options: &PdfOptions) -> String {
+	let metadata = &options.metadata;
+	let mut info = String::from("<<");
+	let entries = [
+		("Title", &metadata.title),
+		("Author", &metadata.author),
+		("Subject", &metadata.subject),
+		("Keywords", &metadata.keywords),
+	];
+	for (key, value) in entries {
+		if let Some(value) = value {
+			info.push_str(&format!(" /{} {}", key, encode_text_string(value)));
+		}
+	}
+	let date = pdf_date(&options.created);
+	info.push_str(&format!(" /Creator {} /Producer {} /CreationDate ({}) /ModDate ({}) >>",
+		encode_text_string(PRODUCER), encode_text_string(PRODUCER), date, date));
+	info
+}
+
+/// XMP metadata mirroring the document information dictionary, as PDF/A
+/// requires both to be consistent.
+fn xmp_metadata(options: &PdfOptions) -> String {
+	let metadata = &options.metadata;
+	let date = options.created.format("%Y-%m-%dT%H:%M:%S%:z");
+	let mut properties = String::new();
+
+	if let Some(title) = &metadata.title {
+		properties.push_str(&format!("<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>\n", escape_xml(title)));
+	}
+	if let Some(author) = &metadata.author {
+		properties.push_str(&format!("<dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>\n", escape_xml(author)));
+	}
+	if let Some(subject) = &metadata.subject {
+		properties.push_str(&format!("<dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>\n", escape_xml(subject)));
+	}
+	if let Some(keywords) = &metadata.keywords {
+		properties.push_str(&format!("<pdf:Keywords>{}</pdf:Keywords>\n", escape_xml(keywords)));
+	}
+	if options.pdfa_icc_profile.is_some() {
+		properties.push_str("<pdfaid:part>2</pdfaid:part>\n<pdfaid:conformance>B</pdfaid:conformance>\n");
+	}
+
+	format!(r#"<?xpacket begin="{}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+<rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:pdf="http://ns.adobe.com/pdf/1.3/" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmlns:pdfaid="http://www.aiim.org/pdfa/ns/id/">
+{}<pdf:Producer>{}</pdf:Producer>
+<xmp:CreatorTool>{}</xmp:CreatorTool>
+<xmp:CreateDate>{}</xmp:CreateDate>
+<xmp:ModifyDate>{}</xmp:ModifyDate>
+<xmp:MetadataDate>{}</xmp:MetadataDate>
+</rdf:Description>
+</rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#, '\u{FEFF}', properties, PRODUCER, PRODUCER, date, date, date)
+}
+
+fn page_content(page: &PdfPage, width: f32, height: f32) -> Vec<u8> {
+	let scale = 72.0 / page.resolution as f32;
+	let mut content = Vec::new();
+	let _ = writeln!(content, "q {:.2} 0 0 {:.2} 0 0 cm /Im0 Do Q", width, height);
+
+	if !page.words.is_empty() {
+		// render mode 3 keeps the text invisible but searchable
+		content.extend_from_slice(b"BT 3 Tr\n");
+		for word in &page.words {
+			let size = (word.height as f32 * scale).max(1.0);
+			let characters = word.text.chars().count().max(1) as f32;
+			// Helvetica glyphs are roughly half as wide as they are high
+			let stretch = 100.0 * (word.width as f32 * scale) / (size * 0.5 * characters);
+			let x = word.left as f32 * scale;
+			let y = height - (word.top + word.height) as f32 * scale;
+			let _ = write!(content, "/F1 {:.2} Tf {:.2} Tz 1 0 0 1 {:.2} {:.2} Tm ", size, stretch, x, y);
+			content.extend_from_slice(&encode_text(&word.text));
+			content.extend_from_slice(b" Tj\n");
+		}
+		content.extend_from_slice(b"ET\n");
+	}
+	content
+}
+
+/// Assembles a PDF with one JPEG image per page and an optional invisible
+/// text layer.
+pub fn build_pdf(pages: &[PdfPage], options: &PdfOptions) -> Result<Vec<u8>, ProcessingError> {
+	let pdfa = options.pdfa_icc_profile.is_some();
+	// PDF/A-2 is based on PDF 1.7
+	let mut writer = PdfWriter::new(if pdfa { "1.7" } else { "1.4" });
+
+	let kids = (0..pages.len())
+		.map(|i| format!("{} 0 R", 4 + 3 * i))
+		.collect::<Vec<String>>()
+		.join(" ");
+
+	let info = 4 + 3 * pages.len();
+	let metadata = info + 1;
+	let icc_profile = info + 2;
+
+	let mut catalog = format!("<< /Type /Catalog /Pages 2 0 R /Metadata {} 0 R", metadata);
+	if pdfa {
+		catalog.push_str(&format!(
+			" /OutputIntents [<< /Type /OutputIntent /S /GTS_PDFA1 /OutputConditionIdentifier (sRGB) /DestOutputProfile {} 0 R >>]",
+			icc_profile));
+	}
+	catalog.push_str(" >>");
+
+	writer.add_object(1, catalog.as_bytes());
+	writer.add_object(2, format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids, pages.len()).as_bytes());
+	writer.add_object(3, b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>");
+
+	for (i, page) in pages.iter().enumerate() {
+		let id = 4 + 3 * i;
+		let info = jpeg_info(&page.image)
+			.ok_or_else(|| ProcessingError::new(&format!("Page {} is not a valid JPEG", i + 1)))?;
+		let scale = 72.0 / page.resolution as f32;
+		let width = info.width as f32 * scale;
+		let height = info.height as f32 * scale;
+
+		writer.add_object(id, format!(
+			"<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources << /XObject << /Im0 {} 0 R >> /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+			width, height, id + 2, id + 1).as_bytes());
+
+		writer.add_stream(id + 1, "", &page_content(page, width, height));
+
+		let color_space = match info.components {
+			1 => "/DeviceGray",
+			4 => "/DeviceCMYK",
+			_ => "/DeviceRGB",
+		};
+		writer.add_stream(id + 2, &format!(
+			"/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {} /BitsPerComponent 8 /Filter /DCTDecode",
+			info.width, info.height, color_space), &page.image);
+	}
+
+	writer.add_object(info, info_dictionary(options).as_bytes());
+	writer.add_stream(metadata, "/Type /Metadata /Subtype /XML", xmp_metadata(options).as_bytes());
+	if let Some(profile) = &options.pdfa_icc_profile {
+		writer.add_stream(icc_profile, "/N 3", profile);
+	}
+
+	Ok(writer.finish(1, info))
+}
+
+#[cfg(test)]
+mod tests {
+	use image::{DynamicImage, GrayImage};
+	use crate::processing::encode_jpeg;
+	use super::*;
+
+	fn options() -> PdfOptions {
+		PdfOptions { metadata: PdfMetadata::default(), created: Local::now(), pdfa_icc_profile: None }
+	}
+
+	fn page(image: Vec<u8>) -> PdfPage {
+		PdfPage { image, resolution: 200, words: Vec::new() }
+	}
+
+	#[test]
+	fn builds_pages() {
+		let jpeg = encode_jpeg(&DynamicImage::ImageLuma8(GrayImage::new(200, 400)), 90).unwrap();
+		let pdf = build_pdf(&[page(jpeg.clone()), page(jpeg)], &options()).unwrap();
+		let text = String::from_utf8_lossy(&pdf);
+		assert!(text.starts_with("%PDF-1.4"));
+		assert!(text.contains("/Count 2"));
+		assert!(text.contains("/MediaBox [0 0 72.00 144.00]"));
+	}
+
+	#[test]
+	fn rejects_corrupt_page() {
+		let jpeg = encode_jpeg(&DynamicImage::ImageLuma8(GrayImage::new(8, 8)), 90).unwrap();
+		let error = build_pdf(&[page(jpeg), page(b"not a jpeg".to_vec())], &options()).unwrap_err();
+		assert_eq!(error.details, "Page 2 is not a valid JPEG");
+	}
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time;
+use reqwest::Url;
+use uuid::Uuid;
+use crate::config::{ConfigError, DestinationConfig, PrinterConfig};
+use crate::discovery::locate;
+use crate::health::HEALTH;
+use crate::helpers::{create_job, download_pages, submit};
+use crate::history::History;
+use crate::hp_api::HpApi;
+use crate::metrics::METRICS;
+use crate::objects::{ApiError, Event, PrinterInfo, WalkupDestination};
+use crate::outbox::Outbox;
+use crate::processing::process;
+
+/// Wait before connecting again to a printer that went away.
+const RECONNECT_DELAY: time::Duration = time::Duration::from_secs(60);
+
+/// A configured printer and its state, shared by its loop and the HTTP
+/// handlers.
+pub struct Printer {
+	pub name: String,
+	config: PrinterConfig,
+	/// Last address the printer answered at, searched again by name or
+	/// serial number once it does not answer there anymore
+	url: Mutex<Option<Url>>,
+	/// Destinations registered on this printer
+	pub destinations: Vec<DestinationConfig>,
+	/// Separate client with a short timeout, so the web interface never
+	/// waits for the long polling loop. Unset until the address is known
+	pub api: Mutex<Option<HpApi>>,
+	/// Client running scan jobs requested over HTTP
+	pub scanner: Mutex<Option<HpApi>>,
+	/// Set by the loop once the printer is reachable
+	pub info: Mutex<Option<PrinterInfo>>,
+	/// Destinations registered on the printer, updated by the loop
+	pub registered: Mutex<Vec<(Uuid, String)>>,
+}
+
+impl Printer {
+	pub fn new(config: &PrinterConfig, destinations: &[DestinationConfig]) -> Result<Printer, ConfigError> {
+		let printer = Printer {
+			name: config.name.clone(),
+			config: config.clone(),
+			url: Mutex::new(None),
+			destinations: config.destinations(destinations),
+			api: Mutex::new(None),
+			scanner: Mutex::new(None),
+			info: Mutex::new(None),
+			registered: Mutex::new(Vec::new()),
+		};
+		if let Some(url) = config.base_url()? {
+			printer.set_url(&url);
+		}
+		Ok(printer)
+	}
+
+	/// Points the clients of the HTTP handlers to a new address.
+	fn set_url(&self, url: &Url) {
+		let mut known = self.url.lock().unwrap();
+		if known.as_ref() == Some(url) {
+			return
+		}
+		if let Some(known) = known.as_ref() {
+			log::info!("Printer {} moved from {} to {}", self.name, known, url);
+		}
+		*known = Some(url.clone());
+		*self.api.lock().unwrap() = Some(HpApi::with_timeout(url.clone(), time::Duration::from_secs(5)).named(&self.name));
+		*self.scanner.lock().unwrap() = Some(HpApi::new(url.clone()).named(&self.name));
+	}
+}
+
+/// Runs the loop of a printer in its own thread. A printer that is offline,
+/// shut down or answers unexpectedly only stops its own loop, which
+/// connects again after a while.
+pub fn watch(printer: Arc<Printer>, outbox: Arc<Outbox>, history: Arc<History>) -> JoinHandle<()> {
+	thread::spawn(move || {
+		let span = tracing::info_span!("printer", printer = %printer.name);
+		let _entered = span.enter();
+		log::debug!("Spawned new thread watching printer {}", printer.name);
+
+		loop {
+			if catch_unwind(AssertUnwindSafe(|| serve(&printer, &outbox, &history))).is_err() {
+				log::error!("Loop of printer {} failed, connecting again", printer.name);
+			}
+			thread::sleep(RECONNECT_DELAY);
+		}
+	})
+}
+
+/// Registers the destinations once the printer is reachable and handles
+/// its scan events until it goes away.
+fn serve(printer: &Printer, outbox: &Outbox, history: &History) {
+	let known = printer.url.lock().unwrap().clone();
+	let api = loop {
+		HEALTH.beat(&printer.name);
+		match locate(&printer.config, known.as_ref()) {
+			Some(api) => break api,
+			None => thread::sleep(RECONNECT_DELAY)
+		}
+	};
+	printer.set_url(api.base_url());
+	let mut api = Registration { api, printer };
+
+	let mut destinations: HashMap<Uuid, DestinationConfig> = HashMap::new();
+	for destination_config in &printer.destinations {
+		let dest = WalkupDestination {
+			hostname: destination_config.name.clone(),
+			name: destination_config.name.clone(),
+			link_type: "Network".to_string(),
+			resource_uri: None,
+			settings: None,
+		};
+
+		match api.add_destination(dest) {
+			Ok(uuid) => {
+				destinations.insert(uuid, destination_config.clone());
+			}
+			Err(e) => log::error!("Could not register destination {}: {}", destination_config.name, e),
+		}
+	}
+	METRICS.destinations_registered.with_label_values(&[&printer.name]).set(destinations.len() as i64);
+	*printer.registered.lock().unwrap() = destinations.iter()
+		.map(|(uuid, destination)| (*uuid, destination.name.clone()))
+		.collect();
+	let info = api.printer_info();
+	*printer.info.lock().unwrap() = Some(info.clone());
+	let _ = api.get_eventtable();
+
+	loop {
+		log::info!("Waiting for job!");
+		HEALTH.beat(&printer.name);
+		let event_table = match api.get_eventtable_timeout(1200) {
+			Ok(event_table) => event_table,
+			Err(e) if api.connection_check() => {
+				log::debug!("Error reading event table. No new events! {}", e);
+				continue
+			}
+			Err(e) => {
+				log::warn!("Printer {} is not reachable anymore: {}", printer.name, e);
+				break
+			}
+		};
+
+		let target_event = "ScanEvent".to_string();
+		let shutdown_event = "PoweringDownEvent".to_string();
+
+		// scanner shutdown handling
+		if event_table.events.iter()
+			.any(|event| event.unqualified_event_category == shutdown_event) {
+			log::info!("Printer {} was shut down", printer.name);
+			break
+		}
+
+		let events = event_table.events.iter()
+			.filter(|event| *event.unqualified_event_category == target_event)
+			.collect::<Vec<&Event>>();
+		for event in events {
+			let target_resource = "wus:WalkupScanToCompDestination".to_string();
+			let Some(payload) = event.payloads.iter()
+				.find(|payload| *payload.resource_type == target_resource) else {
+				log::warn!("Scan event {} names no destination", event.aging_stamp);
+				continue
+			};
+			for destination in api.active_destinations.clone() {
+				if payload.resource_uri.contains(destination.to_string().as_str()) {
+					log::debug!("Scan event triggered for our destination with uuid {}", destination);
+					let Some(destination_config) = destinations.get(&destination) else { continue };
+					// ties every line from the event to the delivery to the scan
+					let span = tracing::info_span!("scan", trigger = "printer", destination = %destination_config.name,
+						event = %event.aging_stamp, scan_id = tracing::field::Empty, job = tracing::field::Empty);
+					let _entered = span.enter();
+					if let Err(e) = start_scanning(&api, destination, destination_config, &info, outbox, history) {
+						log::error!("Scan to {} failed: {}", destination_config.name, e);
+						METRICS.scans_failed.with_label_values(&[&printer.name, &destination_config.name]).inc();
+					}
+				}
+			}
+		}
+	}
+}
+
+/// Client of the loop, removes the registered destinations from the printer
+/// when the loop ends, also if it panicked. They are registered again once
+/// the printer is back.
+struct Registration<'a> {
+	api: HpApi,
+	printer: &'a Printer,
+}
+
+impl Deref for Registration<'_> {
+	type Target = HpApi;
+
+	fn deref(&self) -> &HpApi {
+		&self.api
+	}
+}
+
+impl DerefMut for Registration<'_> {
+	fn deref_mut(&mut self) -> &mut HpApi {
+		&mut self.api
+	}
+}
+
+impl Drop for Registration<'_> {
+	fn drop(&mut self) {
+		self.api.cleanup();
+		METRICS.destinations_registered.with_label_values(&[&self.printer.name]).set(0);
+		self.printer.registered.lock().unwrap().clear();
+		log::info!("Connecting to {} again in {} seconds", self.printer.name, RECONNECT_DELAY.as_secs());
+	}
+}
+
+fn start_scanning(api: &HpApi, target_destination: Uuid, destination_config: &DestinationConfig, printer: &PrinterInfo, outbox: &Outbox, history: &History) -> Result<(), ApiError> {
+	let event = api.get_scantocomp_event()?;
+
+	if event.event_type == "ScanPagesComplete" {
+		log::info!("No more page to scan. Scan is finished");
+		return Ok(())
+	}
+
+	if event.event_type != "ScanRequested" {
+		log::warn!("Unexpected ScanType while scanning {}", event.event_type);
+		return Ok(())
+	}
+	METRICS.scans_started.with_label_values(&[api.name(), &destination_config.name]).inc();
+	let scan_id = history.start_scan(&target_destination.to_string(), &destination_config.name);
+	if let Some(scan_id) = scan_id {
+		tracing::Span::current().record("scan_id", scan_id);
+	}
+
+	let result = scan(api, target_destination, destination_config, printer, outbox, history, scan_id);
+	let (pages, size) = result.as_ref().copied().unwrap_or_default();
+	history.finish_scan(scan_id, pages, size, result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+	result.map(|_| ())
+}
+
+/// Runs the scan job and hands the documents to the outbox. Returns the
+/// number of pages and the size of the documents.
+fn scan(api: &HpApi, target_destination: Uuid, destination_config: &DestinationConfig, printer: &PrinterInfo, outbox: &Outbox, history: &History, scan_id: Option<i64>) -> Result<(usize, usize), ApiError> {
+	let destination = api
+		.get_walkup_destionation(target_destination)
+		.map_err(|e| ApiError::new(&e.to_string()))?;
+
+	let settings = destination.settings.to_owned()
+		.ok_or_else(|| ApiError::new("Settings did not contain no pressed shortcut"))?;
+
+	let scan_status = api.get_scanner_status()?;
+
+	let shortcut = settings.shortcut.clone();
+	let job = create_job(scan_status, settings)?;
+	history.update_settings(scan_id, &shortcut, &job.input_source);
+	let resolution = job.x_resolution as u16;
+	let job_location = api.create_job(job)?;
+
+	log::debug!("New scan job created successfully");
+
+	let pages = download_pages(api, &job_location, &destination_config.name, true)?;
+	let page_count = pages.len();
+	let mut size = 0;
+
+	let documents = process(pages, destination_config, resolution, printer)
+		.map_err(|e| ApiError::new(&format!("Error assembling the document: {}", e)))?;
+	for mut document in documents {
+		document.scan_id = scan_id;
+		size += document.content.len();
+		submit(&document, destination_config, outbox, history);
+	}
+
+	METRICS.scans_completed.with_label_values(&[api.name(), &destination_config.name]).inc();
+	Ok((page_count, size))
+}
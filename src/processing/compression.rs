@@ -0,0 +1,230 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+use crate::config::{CompressionConfig, Oversize};
+use crate::pdf::{build_pdf, PdfOptions, PdfPage, Word};
+use crate::processing::{decode_jpeg, encode_jpeg, ProcessingError};
+
+const DEFAULT_QUALITY: u8 = 85;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Profile {
+	quality: u8,
+	grayscale: bool,
+	max_resolution: Option<u16>,
+}
+
+/// An assembled PDF and the number of pages it contains.
+pub struct Part {
+	pub content: Vec<u8>,
+	pub page_count: usize,
+}
+
+/// Re-encodes the pages with the configured profile. With a maximum size
+/// increasingly stronger profiles are tried until the document fits, after
+/// which it is optionally split into parts that each fit.
+pub fn compress(pages: Vec<PdfPage>, config: &CompressionConfig, max_size: Option<u64>, options: &PdfOptions) -> Result<Vec<Part>, ProcessingError> {
+	let base = Profile {
+		quality: config.jpeg_quality.unwrap_or(DEFAULT_QUALITY),
+		grayscale: config.grayscale,
+		max_resolution: config.max_resolution,
+	};
+
+	let configured = config.jpeg_quality.is_some() || config.grayscale || config.max_resolution.is_some();
+	let mut best = match configured {
+		true => apply_or_keep(&pages, &base),
+		false => pages.clone(),
+	};
+	let mut content = build_pdf(&best, options)?;
+
+	let max_size = match max_size {
+		Some(max_size) => max_size as usize,
+		None => return Ok(vec![Part { content, page_count: best.len() }]),
+	};
+
+	if content.len() > max_size && config.auto {
+		for profile in stronger_profiles(&base) {
+			log::info!("Document has {} bytes, re-encoding with {:?}", content.len(), profile);
+			best = apply_or_keep(&pages, &profile);
+			content = build_pdf(&best, options)?;
+			if content.len() <= max_size {
+				break;
+			}
+		}
+	}
+
+	if content.len() <= max_size || config.oversize != Oversize::Split {
+		return Ok(vec![Part { content, page_count: best.len() }]);
+	}
+
+	log::info!("Document has {} bytes, splitting it into parts of at most {} bytes", content.len(), max_size);
+	split_to_size(best, max_size, options)
+}
+
+/// Profiles tried in order when the document exceeds the maximum size.
+fn stronger_profiles(base: &Profile) -> Vec<Profile> {
+	let mut profiles = Vec::new();
+	for quality in [70, 55, 40] {
+		if quality < base.quality {
+			profiles.push(Profile { quality, ..*base });
+		}
+	}
+	let quality = base.quality.min(55);
+	for resolution in [150, 100] {
+		if base.max_resolution.is_none_or(|max| resolution < max) {
+			profiles.push(Profile { quality, grayscale: base.grayscale, max_resolution: Some(resolution) });
+		}
+	}
+	if !base.grayscale {
+		profiles.push(Profile { quality, grayscale: true, max_resolution: Some(base.max_resolution.unwrap_or(100).min(100)) });
+	}
+	profiles
+}
+
+fn apply_or_keep(pages: &[PdfPage], profile: &Profile) -> Vec<PdfPage> {
+	pages.iter()
+		.map(|page| apply(page, profile).unwrap_or_else(|e| {
+			log::warn!("Could not compress page: {}", e);
+			page.clone()
+		}))
+		.collect()
+}
+
+fn apply(page: &PdfPage, profile: &Profile) -> Result<PdfPage, ProcessingError> {
+	let mut image = decode_jpeg(&page.image)?;
+	if profile.grayscale {
+		image = DynamicImage::ImageLuma8(image.to_luma8());
+	}
+
+	let mut resolution = page.resolution;
+	let mut words = page.words.clone();
+	if let Some(max_resolution) = profile.max_resolution.filter(|max| *max < page.resolution) {
+		let factor = max_resolution as f32 / page.resolution as f32;
+		let width = (image.width() as f32 * factor).round() as u32;
+		let height = (image.height() as f32 * factor).round() as u32;
+		image = image.resize_exact(width, height, FilterType::Triangle);
+		resolution = max_resolution;
+		// the text layer is positioned in image pixels
+		words = words.iter()
+			.map(|word| Word {
+				text: word.text.clone(),
+				left: (word.left as f32 * factor) as u32,
+				top: (word.top as f32 * factor) as u32,
+				width: (word.width as f32 * factor) as u32,
+				height: (word.height as f32 * factor) as u32,
+			})
+			.collect();
+	}
+
+	Ok(PdfPage {
+		image: encode_jpeg(&image, profile.quality)?,
+		resolution,
+		words,
+	})
+}
+
+/// Groups consecutive pages into parts below the maximum size. Fails if a
+/// single page is too large on its own.
+fn split_to_size(pages: Vec<PdfPage>, max_size: usize, options: &PdfOptions) -> Result<Vec<Part>, ProcessingError> {
+	let mut parts = Vec::new();
+	let mut current: Vec<PdfPage> = Vec::new();
+	let mut current_content = Vec::new();
+
+	for (i, page) in pages.into_iter().enumerate() {
+		current.push(page);
+		let mut content = build_pdf(&current, options)?;
+		if content.len() > max_size && current.len() > 1 {
+			let page = current.pop().expect("Part contains the page just added");
+			parts.push(Part { content: current_content, page_count: current.len() });
+			current = vec![page];
+			content = build_pdf(&current, options)?;
+		}
+		if content.len() > max_size {
+			return Err(ProcessingError::new(&format!("Page {} alone has {} bytes, more than the maximum of {} bytes", i + 1, content.len(), max_size)));
+		}
+		current_content = content;
+	}
+
+	if !current.is_empty() {
+		parts.push(Part { content: current_content, page_count: current.len() });
+	}
+	Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::Local;
+	use image::{Rgb, RgbImage};
+	use crate::pdf::PdfMetadata;
+	use super::*;
+
+	fn options() -> PdfOptions {
+		PdfOptions { metadata: PdfMetadata::default(), created: Local::now(), pdfa_icc_profile: None }
+	}
+
+	// noise compresses badly, so the size depends on the page count and quality
+	fn page(seed: u32) -> PdfPage {
+		let image = RgbImage::from_fn(300, 300, |x, y| {
+			let value = (x.wrapping_mul(7919) ^ y.wrapping_mul(104729) ^ seed.wrapping_mul(31337)).wrapping_mul(2654435761) >> 24;
+			Rgb([value as u8, (value >> 1) as u8, 255 - value as u8])
+		});
+		PdfPage {
+			image: encode_jpeg(&DynamicImage::ImageRgb8(image), 95).unwrap(),
+			resolution: 300,
+			words: Vec::new(),
+		}
+	}
+
+	fn config(toml: &str) -> CompressionConfig {
+		toml::from_str(toml).unwrap()
+	}
+
+	#[test]
+	fn keeps_documents_without_maximum_size() {
+		let parts = compress(vec![page(1), page(2)], &config(""), None, &options()).unwrap();
+		assert_eq!(parts.len(), 1);
+		assert_eq!(parts[0].page_count, 2);
+	}
+
+	#[test]
+	fn reencodes_until_document_fits() {
+		let pages = vec![page(1), page(2)];
+		let original = build_pdf(&pages, &options()).unwrap().len();
+		let max_size = original * 2 / 3;
+
+		let parts = compress(pages, &config(""), Some(max_size as u64), &options()).unwrap();
+		assert_eq!(parts.len(), 1);
+		assert_eq!(parts[0].page_count, 2);
+		assert!(parts[0].content.len() <= max_size, "{} > {}", parts[0].content.len(), max_size);
+	}
+
+	#[test]
+	fn splits_into_parts_that_fit() {
+		let pages = (0..5).map(page).collect::<Vec<PdfPage>>();
+		let single = build_pdf(&pages[..1], &options()).unwrap().len();
+		let max_size = single * 5 / 2;
+
+		let parts = compress(pages, &config("auto = false"), Some(max_size as u64), &options()).unwrap();
+		assert!(parts.len() > 1);
+		assert_eq!(parts.iter().map(|part| part.page_count).sum::<usize>(), 5);
+		for part in &parts {
+			assert!(part.page_count > 0);
+			assert!(part.content.len() <= max_size, "{} > {}", part.content.len(), max_size);
+		}
+	}
+
+	#[test]
+	fn fails_on_single_page_above_maximum_size() {
+		let pages = vec![page(1), page(2)];
+		let single = build_pdf(&pages[..1], &options()).unwrap().len();
+
+		let error = compress(pages, &config("auto = false"), Some(single as u64 / 2), &options()).err().unwrap();
+		assert!(error.to_string().starts_with("Page 1 alone has"), "{}", error);
+	}
+
+	#[test]
+	fn keeps_oversize_documents_for_fallback() {
+		let parts = compress(vec![page(1)], &config("auto = false\noversize = \"fallback\""), Some(100), &options()).unwrap();
+		assert_eq!(parts.len(), 1);
+		assert!(parts[0].content.len() > 100);
+	}
+}
@@ -0,0 +1,263 @@
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, ImageBuffer, Pixel};
+use crate::config::DeskewConfig;
+use crate::processing::{decode_jpeg, encode_jpeg, run_with_timeout, ProcessingError, ScannedPage};
+
+const OSD_TIMEOUT: Duration = Duration::from_secs(60);
+const MIN_OSD_CONFIDENCE: f32 = 2.0;
+// skew below this is not worth recompressing the page for
+const MIN_SKEW_ANGLE: f32 = 0.1;
+// text lines are still clearly visible at this width and it keeps the search fast
+const ANALYSIS_WIDTH: u32 = 800;
+
+/// Rotates the page upright and corrects small skew angles.
+pub fn straighten(page: &mut ScannedPage, config: &DeskewConfig) -> Result<(), ProcessingError> {
+	let mut rotation = match config.use_orientation_hint {
+		true => hint_rotation(&page.orientation),
+		false => 0,
+	};
+
+	if config.detect_orientation {
+		match detect_rotation(&page.image, &config.tesseract) {
+			Ok(detected) => rotation = detected,
+			Err(e) => log::debug!("Orientation detection failed for page {}: {}", page.number, e),
+		}
+	}
+
+	let mut image = decode_jpeg(&page.image)?;
+	image = match rotation {
+		90 => image.rotate90(),
+		180 => image.rotate180(),
+		270 => image.rotate270(),
+		_ => image,
+	};
+
+	let angle = detect_skew(&image.to_luma8(), config.max_angle);
+	if rotation == 0 && angle.abs() < MIN_SKEW_ANGLE {
+		log::debug!("Page {} is already straight", page.number);
+		return Ok(());
+	}
+
+	log::info!("Rotating page {} by {} degrees and correcting a skew of {:.2} degrees", page.number, rotation, angle);
+
+	if angle.abs() >= MIN_SKEW_ANGLE {
+		image = rotate(&image, angle);
+	}
+
+	page.image = encode_jpeg(&image, config.jpeg_quality)?;
+	Ok(())
+}
+
+/// Maps the `ImageOrientation` of a `PreScanPage` to the clockwise rotation
+/// needed to turn the page upright.
+fn hint_rotation(orientation: &str) -> u32 {
+	if orientation.contains("270") {
+		270
+	} else if orientation.contains("180") {
+		180
+	} else if orientation.contains("90") {
+		90
+	} else {
+		0
+	}
+}
+
+/// Uses tesseract's orientation and script detection and returns the
+/// clockwise rotation needed to turn the page upright.
+fn detect_rotation(image: &[u8], tesseract: &str) -> Result<u32, ProcessingError> {
+	let dir = tempfile::tempdir()
+		.map_err(|e| ProcessingError::new(&format!("Error creating temp dir: {}", e)))?;
+	let input = dir.path().join("page.jpg");
+	let output = dir.path().join("page");
+	fs::write(&input, image)
+		.map_err(|e| ProcessingError::new(&format!("Error writing page image: {}", e)))?;
+
+	let mut command = Command::new(tesseract);
+	command.arg(&input)
+		.arg(&output)
+		.args(["--psm", "0"]);
+	run_with_timeout(&mut command, OSD_TIMEOUT)?;
+
+	let osd = fs::read_to_string(output.with_extension("osd"))
+		.map_err(|e| ProcessingError::new(&format!("Error reading orientation result: {}", e)))?;
+
+	let value = |key: &str| osd.lines()
+		.find_map(|line| line.strip_prefix(key))
+		.and_then(|value| value.trim().parse::<f32>().ok());
+
+	let rotation = value("Rotate:")
+		.ok_or(ProcessingError::new("Orientation result did not contain a rotation"))?;
+	let confidence = value("Orientation confidence:").unwrap_or(0.0);
+
+	if confidence < MIN_OSD_CONFIDENCE {
+		return Err(ProcessingError::new(&format!("Orientation confidence {} too low", confidence)));
+	}
+
+	Ok(rotation as u32 % 360)
+}
+
+/// Finds the skew angle in degrees by rotating the dark pixels and looking
+/// for the angle with the sharpest horizontal projection profile, which is
+/// where the text lines run horizontally.
+fn detect_skew(image: &GrayImage, max_angle: f32) -> f32 {
+	let scale = ANALYSIS_WIDTH as f32 / image.width() as f32;
+	let small = match scale < 1.0 {
+		true => image::imageops::resize(image, ANALYSIS_WIDTH, (image.height() as f32 * scale) as u32, FilterType::Triangle),
+		false => image.clone(),
+	};
+
+	let cx = small.width() as f32 / 2.0;
+	let cy = small.height() as f32 / 2.0;
+	let dark = small.enumerate_pixels()
+		.filter(|(_, _, pixel)| pixel.0[0] < 128)
+		.map(|(x, y, _)| (x as f32 - cx, y as f32 - cy))
+		.collect::<Vec<(f32, f32)>>();
+
+	if dark.len() < 100 {
+		return 0.0;
+	}
+
+	let diagonal = (cx * cx + cy * cy).sqrt();
+	let mut bins = vec![0u32; 2 * diagonal as usize + 2];
+	let mut score = |degrees: f32| {
+		let (sin, cos) = degrees.to_radians().sin_cos();
+		bins.iter_mut().for_each(|bin| *bin = 0);
+		for (x, y) in &dark {
+			let row = y * cos - x * sin + diagonal;
+			bins[row as usize] += 1;
+		}
+		bins.iter().map(|&bin| (bin as u64) * (bin as u64)).sum::<u64>()
+	};
+
+	let search = |score: &mut dyn FnMut(f32) -> u64, from: f32, to: f32, step: f32| {
+		let mut best = (0.0, 0);
+		let mut angle = from;
+		while angle <= to {
+			let value = score(angle);
+			if value > best.1 {
+				best = (angle, value);
+			}
+			angle += step;
+		}
+		best.0
+	};
+
+	let coarse = search(&mut score, -max_angle, max_angle, 0.5);
+	search(&mut score, coarse - 0.5, coarse + 0.5, 0.05)
+}
+
+fn rotate(image: &DynamicImage, degrees: f32) -> DynamicImage {
+	match image {
+		DynamicImage::ImageLuma8(buffer) => DynamicImage::ImageLuma8(rotate_buffer(buffer, degrees)),
+		_ => DynamicImage::ImageRgb8(rotate_buffer(&image.to_rgb8(), degrees)),
+	}
+}
+
+/// Rotates around the center with bilinear sampling, keeping the page size.
+/// Uncovered corners are filled with white.
+fn rotate_buffer<P: Pixel<Subpixel = u8>>(source: &ImageBuffer<P, Vec<u8>>, degrees: f32) -> ImageBuffer<P, Vec<u8>> {
+	let (width, height) = source.dimensions();
+	let (sin, cos) = degrees.to_radians().sin_cos();
+	let cx = width as f32 / 2.0;
+	let cy = height as f32 / 2.0;
+	let channels = P::CHANNEL_COUNT as usize;
+	let white = *P::from_slice(&[255u8; 4][..channels]);
+
+	ImageBuffer::from_fn(width, height, |x, y| {
+		let dx = x as f32 - cx;
+		let dy = y as f32 - cy;
+		let sx = dx * cos - dy * sin + cx;
+		let sy = dx * sin + dy * cos + cy;
+
+		if sx < 0.0 || sy < 0.0 || sx >= (width - 1) as f32 || sy >= (height - 1) as f32 {
+			return white;
+		}
+
+		let (x0, y0) = (sx as u32, sy as u32);
+		let (fx, fy) = (sx - x0 as f32, sy - y0 as f32);
+		let p00 = source.get_pixel(x0, y0).channels();
+		let p10 = source.get_pixel(x0 + 1, y0).channels();
+		let p01 = source.get_pixel(x0, y0 + 1).channels();
+		let p11 = source.get_pixel(x0 + 1, y0 + 1).channels();
+
+		let mut values = [0u8; 4];
+		for c in 0..channels {
+			let top = p00[c] as f32 * (1.0 - fx) + p10[c] as f32 * fx;
+			let bottom = p01[c] as f32 * (1.0 - fx) + p11[c] as f32 * fx;
+			values[c] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+		}
+		*P::from_slice(&values[..channels])
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use image::Luma;
+	use super::*;
+
+	// text-like rows of dark lines on a white page
+	fn lines() -> GrayImage {
+		GrayImage::from_fn(800, 600, |x, y| match (100..700).contains(&x) && (100..500).contains(&y) && y % 30 < 3 {
+			true => Luma([0]),
+			false => Luma([255]),
+		})
+	}
+
+	#[test]
+	fn detects_skew_of_rotated_lines() {
+		for angle in [-3.0, 1.5, 4.0] {
+			let skewed = rotate_buffer(&lines(), angle);
+			let detected = detect_skew(&skewed, 5.0);
+			assert!((detected + angle).abs() < 0.2, "rotated by {}, detected {}", angle, detected);
+		}
+	}
+
+	#[test]
+	fn detects_no_skew_of_straight_lines() {
+		assert!(detect_skew(&lines(), 5.0).abs() < 0.1);
+	}
+
+	#[test]
+	fn ignores_blank_pages() {
+		assert_eq!(detect_skew(&GrayImage::from_pixel(800, 600, Luma([255])), 5.0), 0.0);
+	}
+
+	#[test]
+	fn rotates_around_the_center() {
+		let mut source = GrayImage::from_pixel(101, 101, Luma([255]));
+		source.put_pixel(50, 20, Luma([0]));
+
+		let unchanged = rotate_buffer(&source, 0.0);
+		assert_eq!(unchanged, source);
+
+		// a quarter turn moves the dot from above the center to its side
+		let rotated = rotate_buffer(&source, 90.0);
+		let dark = rotated.enumerate_pixels()
+			.filter(|(_, _, pixel)| pixel.0[0] < 128)
+			.map(|(x, y, _)| (x, y))
+			.collect::<Vec<(u32, u32)>>();
+		assert_eq!(dark.len(), 1);
+		let (x, y) = dark[0];
+		assert!(x.abs_diff(50) >= 29 && y.abs_diff(50) <= 1, "dot at {},{}", x, y);
+		assert_eq!(rotated.dimensions(), (101, 101));
+	}
+
+	#[test]
+	fn fills_uncovered_corners_with_white() {
+		let rotated = rotate_buffer(&GrayImage::from_pixel(100, 100, Luma([0])), 45.0);
+		assert_eq!(rotated.get_pixel(0, 0).0[0], 255);
+		assert_eq!(rotated.get_pixel(50, 50).0[0], 0);
+	}
+
+	#[test]
+	fn maps_orientation_hints() {
+		assert_eq!(hint_rotation("0"), 0);
+		assert_eq!(hint_rotation(""), 0);
+		assert_eq!(hint_rotation("90"), 90);
+		assert_eq!(hint_rotation("Rotate180"), 180);
+		assert_eq!(hint_rotation("270"), 270);
+	}
+}
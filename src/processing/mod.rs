@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use wait_timeout::ChildExt;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use crate::config::DestinationConfig;
+use crate::objects::PrinterInfo;
+use crate::pdf::{build_pdf, PdfMetadata, PdfOptions, PdfPage};
+use crate::processing::compression::Part;
+use crate::processing::split::PageGroup;
+use crate::template::{format_size, render, sanitize_filename};
+
+pub mod compression;
+pub mod deskew;
+pub mod ocr;
+pub mod split;
+
+const THUMBNAIL_SIZE: u32 = 240;
+const DEFAULT_FILENAME: &str = "Scan_{{date}}Z{{time}}.pdf";
+
+pub struct ScannedPage {
+	pub number: i32,
+	pub image: Vec<u8>,
+	pub orientation: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Document {
+	pub filename: String,
+	/// Kept out of the serialized form, the outbox stores it as a separate file
+	#[serde(skip)]
+	pub content: Vec<u8>,
+	pub page_count: usize,
+	/// Name of the walk-up destination the document was scanned to
+	pub destination: String,
+	/// Host name of the printer that scanned the document
+	pub printer: String,
+	pub printer_model: String,
+	pub printer_serial: String,
+	pub created: DateTime<Local>,
+	/// Scan job in the history the document was produced by
+	#[serde(default)]
+	pub scan_id: Option<i64>,
+	/// Small JPEG of the first page for the web interface
+	#[serde(skip)]
+	pub thumbnail: Option<Vec<u8>>,
+}
+
+impl Document {
+	/// Values available to templates of sinks.
+	pub fn template_values(&self) -> HashMap<&str, String> {
+		let mut values = HashMap::new();
+		values.insert("filename", self.filename.clone());
+		values.insert("destination", self.destination.clone());
+		values.insert("pages", self.page_count.to_string());
+		values.insert("date", self.created.format("%F").to_string());
+		values.insert("time", self.created.format("%H-%M").to_string());
+		values.insert("size", self.content.len().to_string());
+		values.insert("size_human", format_size(self.content.len() as u64));
+		values.insert("printer", self.printer.clone());
+		values.insert("printer_model", self.printer_model.clone());
+		values.insert("printer_serial", self.printer_serial.clone());
+		values.insert("timestamp", self.created.to_rfc3339());
+		values
+	}
+}
+
+/// Runs the post-processing stages configured for the destination and
+/// assembles the scanned pages into one or more PDFs.
+pub fn process(mut pages: Vec<ScannedPage>, destination: &DestinationConfig, resolution: u16, printer: &PrinterInfo) -> Result<Vec<Document>, ProcessingError> {
+	if let Some(deskew_config) = &destination.deskew {
+		for page in pages.iter_mut() {
+			if let Err(e) = deskew::straighten(page, deskew_config) {
+				log::warn!("Could not straighten page {}: {}", page.number, e);
+			}
+		}
+	}
+
+	let groups = match &destination.split {
+		Some(split_config) => split::split(pages, split_config),
+		None => vec![PageGroup { pages, barcode: None }],
+	};
+
+	if groups.iter().all(|group| group.pages.is_empty()) {
+		log::warn!("No pages left after splitting, nothing to deliver");
+		return Err(ProcessingError::new("Every page was blank or a separator sheet"));
+	}
+
+	if groups.len() > 1 {
+		log::info!("Split scan job into {} documents", groups.len());
+	}
+
+	let now = chrono::offset::Local::now();
+	let template = destination.filename.as_deref().unwrap_or(DEFAULT_FILENAME);
+	let document_count = groups.len();
+
+	let documents = groups.into_iter()
+		.enumerate()
+		.map(|(i, group)| {
+			let mut values = HashMap::new();
+			values.insert("date", now.format("%F").to_string());
+			values.insert("time", now.format("%H-%M").to_string());
+			values.insert("destination", sanitize_filename(&destination.name));
+			values.insert("index", (i + 1).to_string());
+			values.insert("pages", group.pages.len().to_string());
+			values.insert("barcode", group.barcode.as_deref().map(sanitize_filename).unwrap_or_default());
+			values.insert("printer", printer.host.clone());
+			values.insert("printer_model", printer.model.clone());
+			values.insert("printer_serial", printer.serial.clone());
+
+			let mut filename = render(template, &values);
+			// split documents need distinct names even if the template does not contain an index
+			if document_count > 1 && !template.contains("index") {
+				filename = match filename.strip_suffix(".pdf") {
+					Some(stem) => format!("{}_{}.pdf", stem, i + 1),
+					None => format!("{}_{}", filename, i + 1),
+				};
+			}
+
+			values.insert("filename", filename.clone());
+
+			assemble(group.pages, destination, resolution, printer, now, &values)
+		})
+		.collect::<Result<Vec<Vec<Document>>, ProcessingError>>()?;
+	Ok(documents.into_iter().flatten().collect())
+}
+
+/// Assembles the pages into a PDF. Documents exceeding the configured
+/// maximum size can come back as several parts.
+fn assemble(pages: Vec<ScannedPage>, destination: &DestinationConfig, resolution: u16, printer: &PrinterInfo, created: DateTime<Local>, values: &HashMap<&str, String>) -> Result<Vec<Document>, ProcessingError> {
+	let mut words = vec![Vec::new(); pages.len()];
+
+	if let Some(ocr_config) = &destination.ocr {
+		match ocr::recognize(&pages, ocr_config, resolution) {
+			Ok(recognized) => words = recognized,
+			Err(e) => log::warn!("OCR failed, delivering document without text layer: {}", e),
+		}
+	}
+
+	let thumbnail = pages.first()
+		.and_then(|page| match thumbnail(&page.image) {
+			Ok(thumbnail) => Some(thumbnail),
+			Err(e) => {
+				log::warn!("Could not create thumbnail: {}", e);
+				None
+			}
+		});
+
+	let pdf_pages = pages.into_iter()
+		.zip(words)
+		.map(|(page, words)| PdfPage {
+			image: page.image,
+			resolution,
+			words,
+		})
+		.collect::<Vec<PdfPage>>();
+
+	let options = pdf_options(destination, created, values)?;
+	let filename = &values["filename"];
+
+	let parts = match &destination.compression {
+		Some(compression_config) => compression::compress(pdf_pages, compression_config, destination.max_document_size(), &options)?,
+		None => vec![Part { content: build_pdf(&pdf_pages, &options)?, page_count: pdf_pages.len() }],
+	};
+
+	let part_count = parts.len();
+	Ok(parts.into_iter()
+		.enumerate()
+		.map(|(i, part)| Document {
+			filename: match part_count {
+				1 => filename.clone(),
+				_ => match filename.strip_suffix(".pdf") {
+					Some(stem) => format!("{}_part{}.pdf", stem, i + 1),
+					None => format!("{}_part{}", filename, i + 1),
+				},
+			},
+			content: part.content,
+			page_count: part.page_count,
+			destination: destination.name.clone(),
+			printer: printer.host.clone(),
+			printer_model: printer.model.clone(),
+			printer_serial: printer.serial.clone(),
+			created,
+			scan_id: None,
+			thumbnail: thumbnail.clone(),
+		})
+		.collect())
+}
+
+fn pdf_options(destination: &DestinationConfig, created: DateTime<Local>, values: &HashMap<&str, String>) -> Result<PdfOptions, ProcessingError> {
+	let pdf_config = match &destination.pdf {
+		Some(pdf_config) => pdf_config,
+		None => return Ok(PdfOptions { metadata: PdfMetadata::default(), created, pdfa_icc_profile: None }),
+	};
+
+	let field = |template: &Option<String>| template.as_deref().map(|template| render(template, values));
+	let metadata = PdfMetadata {
+		title: field(&pdf_config.title),
+		author: field(&pdf_config.author),
+		subject: field(&pdf_config.subject),
+		keywords: field(&pdf_config.keywords),
+	};
+
+	// checked at startup, a profile removed since then fails the document
+	// instead of silently producing one that is not PDF/A
+	let pdfa_icc_profile = match pdf_config.pdfa {
+		true => Some(fs::read(&pdf_config.icc_profile)
+			.map_err(|e| ProcessingError::new(&format!("Error reading ICC profile {}: {}", pdf_config.icc_profile, e)))?),
+		false => None,
+	};
+
+	Ok(PdfOptions { metadata, created, pdfa_icc_profile })
+}
+
+pub fn decode_jpeg(data: &[u8]) -> Result<DynamicImage, ProcessingError> {
+	image::load_from_memory_with_format(data, ImageFormat::Jpeg)
+		.map_err(|e| ProcessingError::new(&format!("Error decoding page image: {}", e)))
+}
+
+pub fn encode_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, ProcessingError> {
+	let mut buffer = Vec::new();
+	image.write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, quality))
+		.map_err(|e| ProcessingError::new(&format!("Error encoding page image: {}", e)))?;
+	Ok(buffer)
+}
+
+pub fn thumbnail(data: &[u8]) -> Result<Vec<u8>, ProcessingError> {
+	let image = decode_jpeg(data)?;
+	encode_jpeg(&image.resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle), 75)
+}
+
+/// Runs an external tool and kills it once the timeout expires. Returns
+/// what the tool wrote to stdout, which must be small enough for the pipe
+/// buffer as it is only read after the tool exited.
+pub fn run_with_timeout(command: &mut Command, timeout: Duration) -> Result<String, ProcessingError> {
+	let program = command.get_program().to_string_lossy().to_string();
+
+	let mut child = command
+		.stdout(Stdio::piped())
+		.stderr(Stdio::null())
+		.spawn()
+		.map_err(|e| ProcessingError::new(&format!("Error starting {}: {}", program, e)))?;
+
+	match child.wait_timeout(timeout) {
+		Ok(Some(status)) if status.success() => {
+			let mut output = String::new();
+			if let Some(mut stdout) = child.stdout.take() {
+				let _ = stdout.read_to_string(&mut output);
+			}
+			Ok(output)
+		}
+		Ok(Some(status)) => Err(ProcessingError::new(&format!("{} exited with {}", program, status))),
+		Ok(None) => {
+			let _ = child.kill();
+			let _ = child.wait();
+			Err(ProcessingError::new(&format!("{} did not finish within {} seconds", program, timeout.as_secs())))
+		}
+		Err(e) => Err(ProcessingError::new(&format!("Error waiting for {}: {}", program, e))),
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessingError {
+	pub details: String,
+}
+
+impl ProcessingError {
+	pub fn new(msg: &str) -> ProcessingError {
+		ProcessingError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for ProcessingError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.details)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use image::{GrayImage, Luma};
+	use crate::config::SplitConfig;
+	use super::*;
+
+	#[test]
+	fn fails_without_pages_left() {
+		let page = ScannedPage {
+			number: 1,
+			image: encode_jpeg(&DynamicImage::ImageLuma8(GrayImage::from_pixel(200, 280, Luma([255]))), 90).unwrap(),
+			orientation: String::new(),
+		};
+		let destination = DestinationConfig {
+			name: "Office".to_string(),
+			split: Some(toml::from_str::<SplitConfig>("blank_pages = true").unwrap()),
+			..DestinationConfig::default()
+		};
+		let printer = PrinterInfo { host: "printer".to_string(), model: String::new(), serial: String::new() };
+
+		let error = process(vec![page], &destination, 200, &printer).err().unwrap();
+		assert_eq!(error.to_string(), "Every page was blank or a separator sheet");
+	}
+}
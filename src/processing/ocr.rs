@@ -0,0 +1,95 @@
+use std::fs;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use crate::config::OcrConfig;
+use crate::pdf::Word;
+use crate::processing::{run_with_timeout, ProcessingError, ScannedPage};
+
+/// Runs tesseract on every page and returns the recognized words per page.
+/// The timeout applies to the whole document, not to single pages.
+pub fn recognize(pages: &[ScannedPage], config: &OcrConfig, resolution: u16) -> Result<Vec<Vec<Word>>, ProcessingError> {
+	log::info!("Running OCR on {} pages with languages {}", pages.len(), config.languages.join(", "));
+
+	let deadline = Instant::now() + Duration::from_secs(config.timeout_secs);
+	let dir = tempfile::tempdir()
+		.map_err(|e| ProcessingError::new(&format!("Error creating temp dir: {}", e)))?;
+	let languages = config.languages.join("+");
+
+	let mut recognized = Vec::new();
+	for (i, page) in pages.iter().enumerate() {
+		let image = dir.path().join(format!("page-{}.jpg", i));
+		let output = dir.path().join(format!("page-{}", i));
+		fs::write(&image, &page.image)
+			.map_err(|e| ProcessingError::new(&format!("Error writing page image: {}", e)))?;
+
+		let mut command = Command::new(&config.tesseract);
+		command.arg(&image)
+			.arg(&output)
+			.args(["-l", &languages, "--dpi", &resolution.to_string(), "tsv"]);
+
+		let remaining = deadline.saturating_duration_since(Instant::now());
+		run_with_timeout(&mut command, remaining)?;
+
+		let tsv = fs::read_to_string(output.with_extension("tsv"))
+			.map_err(|e| ProcessingError::new(&format!("Error reading OCR result: {}", e)))?;
+		let words = parse_tsv(&tsv);
+		log::debug!("Recognized {} words on page {}", words.len(), page.number);
+		recognized.push(words);
+	}
+
+	Ok(recognized)
+}
+
+/// Extracts the words from tesseract's TSV output. The columns are level,
+/// page, block, paragraph, line, word, left, top, width, height, confidence
+/// and text.
+fn parse_tsv(tsv: &str) -> Vec<Word> {
+	tsv.lines()
+		.skip(1)
+		.filter_map(|line| {
+			let columns = line.split('\t').collect::<Vec<&str>>();
+			if columns.len() < 12 || columns[0] != "5" {
+				return None;
+			}
+			let confidence = columns[10].parse::<f32>().ok()?;
+			let text = columns[11].trim();
+			if confidence < 0.0 || text.is_empty() {
+				return None;
+			}
+			Some(Word {
+				text: text.to_string(),
+				left: columns[6].parse().ok()?,
+				top: columns[7].parse().ok()?,
+				width: columns[8].parse().ok()?,
+				height: columns[9].parse().ok()?,
+			})
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_words() {
+		let tsv = "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext\n\
+			1\t1\t0\t0\t0\t0\t0\t0\t1700\t2200\t-1\t\n\
+			4\t1\t1\t1\t1\t0\t100\t200\t300\t40\t-1\t\n\
+			5\t1\t1\t1\t1\t1\t100\t200\t120\t40\t96.5\tRechnung\n\
+			5\t1\t1\t1\t1\t2\t230\t202\t80\t38\t91\tNr. \n\
+			5\t1\t1\t1\t1\t3\t320\t202\t10\t38\t95\t \n\
+			5\t1\t1\t1\t1\t4\t340\t202\t10\t38\t-1\tx\n\
+			5\t1\t1\t1\t1\t5\tbroken\n";
+		assert_eq!(parse_tsv(tsv), vec![
+			Word { text: "Rechnung".to_string(), left: 100, top: 200, width: 120, height: 40 },
+			Word { text: "Nr.".to_string(), left: 230, top: 202, width: 80, height: 38 },
+		]);
+	}
+
+	#[test]
+	fn ignores_empty_output() {
+		assert!(parse_tsv("").is_empty());
+		assert!(parse_tsv("level\tpage_num\n").is_empty());
+	}
+}
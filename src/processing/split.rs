@@ -0,0 +1,175 @@
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+use image::imageops::FilterType;
+use crate::config::SplitConfig;
+use crate::processing::{decode_jpeg, run_with_timeout, ProcessingError, ScannedPage};
+
+const BARCODE_TIMEOUT: Duration = Duration::from_secs(30);
+// scanner shadows at the page borders should not count as content
+const MARGIN: f32 = 0.05;
+
+/// Pages belonging to one document of a split scan job.
+pub struct PageGroup {
+	pub pages: Vec<ScannedPage>,
+	/// Contents of the barcode on the separator page that started this group
+	pub barcode: Option<String>,
+}
+
+/// Splits a scan job at separator pages. Separator pages are dropped and
+/// groups without any pages are skipped.
+pub fn split(pages: Vec<ScannedPage>, config: &SplitConfig) -> Vec<PageGroup> {
+	let mut groups = vec![PageGroup { pages: Vec::new(), barcode: None }];
+
+	for page in pages {
+		let separator = match config.barcode {
+			true => find_separator_code(&page, config),
+			false => None,
+		};
+
+		if let Some(code) = separator {
+			log::info!("Page {} is a separator sheet with barcode {}", page.number, code);
+			groups.push(PageGroup { pages: Vec::new(), barcode: Some(code) });
+			continue;
+		}
+
+		if config.blank_pages {
+			match is_blank(&page, config.blank_threshold) {
+				Ok(true) => {
+					log::info!("Page {} is a blank separator page", page.number);
+					groups.push(PageGroup { pages: Vec::new(), barcode: None });
+					continue;
+				}
+				Ok(false) => {}
+				Err(e) => log::warn!("Could not check page {} for content: {}", page.number, e),
+			}
+		}
+
+		groups.last_mut()
+			.expect("There is always a group")
+			.pages
+			.push(page);
+	}
+
+	groups.into_iter()
+		.filter(|group| !group.pages.is_empty())
+		.collect()
+}
+
+/// A page is blank if the share of dark pixels is below the threshold.
+fn is_blank(page: &ScannedPage, threshold: f32) -> Result<bool, ProcessingError> {
+	let image = decode_jpeg(&page.image)?;
+	let small = image.resize(400, 400, FilterType::Triangle).to_luma8();
+
+	let (width, height) = small.dimensions();
+	let (margin_x, margin_y) = ((width as f32 * MARGIN) as u32, (height as f32 * MARGIN) as u32);
+	let mut total = 0;
+	let mut dark = 0;
+	for (x, y, pixel) in small.enumerate_pixels() {
+		if x < margin_x || y < margin_y || x >= width - margin_x || y >= height - margin_y {
+			continue;
+		}
+		total += 1;
+		if pixel.0[0] < 128 {
+			dark += 1;
+		}
+	}
+
+	Ok(total == 0 || (dark as f32 / total as f32) < threshold)
+}
+
+/// Returns the first barcode on the page matching the configured prefix.
+fn find_separator_code(page: &ScannedPage, config: &SplitConfig) -> Option<String> {
+	let codes = match read_barcodes(&page.image, &config.zbarimg) {
+		Ok(codes) => codes,
+		Err(e) => {
+			log::debug!("No barcode found on page {}: {}", page.number, e);
+			return None;
+		}
+	};
+
+	separator_code(codes, &config.barcode_prefix)
+}
+
+/// Any barcode would match an empty prefix, which the configuration rejects.
+fn separator_code(codes: Vec<String>, prefix: &str) -> Option<String> {
+	codes.into_iter()
+		.find(|code| !prefix.is_empty() && code.starts_with(prefix))
+}
+
+/// Reads all barcodes and QR codes on the image with zbarimg.
+fn read_barcodes(image: &[u8], zbarimg: &str) -> Result<Vec<String>, ProcessingError> {
+	let dir = tempfile::tempdir()
+		.map_err(|e| ProcessingError::new(&format!("Error creating temp dir: {}", e)))?;
+	let input = dir.path().join("page.jpg");
+	fs::write(&input, image)
+		.map_err(|e| ProcessingError::new(&format!("Error writing page image: {}", e)))?;
+
+	let mut command = Command::new(zbarimg);
+	command.args(["--quiet", "--raw"])
+		.arg(&input);
+	let output = run_with_timeout(&mut command, BARCODE_TIMEOUT)?;
+
+	Ok(output.lines()
+		.map(|line| line.trim().to_string())
+		.filter(|line| !line.is_empty())
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use image::{DynamicImage, GrayImage, Luma};
+	use crate::processing::encode_jpeg;
+	use super::*;
+
+	/// A white page, with a dark block in the middle if `ink` is set.
+	fn page(number: i32, ink: bool) -> ScannedPage {
+		let mut image = GrayImage::from_pixel(200, 280, Luma([255]));
+		if ink {
+			for x in 60..140 {
+				for y in 100..180 {
+					image.put_pixel(x, y, Luma([0]));
+				}
+			}
+		}
+		ScannedPage {
+			number,
+			image: encode_jpeg(&DynamicImage::ImageLuma8(image), 90).unwrap(),
+			orientation: String::new(),
+		}
+	}
+
+	fn config() -> SplitConfig {
+		SplitConfig {
+			blank_pages: true,
+			blank_threshold: 0.005,
+			barcode: false,
+			barcode_prefix: "PATCH".to_string(),
+			zbarimg: "zbarimg".to_string(),
+		}
+	}
+
+	#[test]
+	fn detects_blank_pages() {
+		assert!(is_blank(&page(1, false), 0.005).unwrap());
+		assert!(!is_blank(&page(1, true), 0.005).unwrap());
+	}
+
+	#[test]
+	fn splits_at_blank_pages() {
+		let pages = vec![page(1, true), page(2, false), page(3, true), page(4, true), page(5, false)];
+		let groups = split(pages, &config());
+		let numbers = groups.iter()
+			.map(|group| group.pages.iter().map(|page| page.number).collect::<Vec<i32>>())
+			.collect::<Vec<_>>();
+		assert_eq!(numbers, vec![vec![1], vec![3, 4]]);
+	}
+
+	#[test]
+	fn only_prefixed_barcodes_separate() {
+		let codes = vec!["https://example.com/invoice".to_string(), "PATCH-T".to_string()];
+		assert_eq!(separator_code(codes.clone(), "PATCH"), Some("PATCH-T".to_string()));
+		assert_eq!(separator_code(codes[..1].to_vec(), "PATCH"), None);
+		assert_eq!(separator_code(codes, ""), None);
+	}
+}
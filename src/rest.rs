@@ -0,0 +1,205 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::config::DestinationConfig;
+use crate::helpers::{download_pages, job_settings, submit, Color, Content, Source};
+use crate::metrics::METRICS;
+use crate::objects::ApiError;
+use crate::processing::{process, Document};
+use crate::server::ServerState;
+
+/// Destination name of scans returned in the response.
+const API_DESTINATION: &str = "api";
+
+/// Body of `POST /api/scans`, every field is optional.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ScanRequest {
+	/// Name of the printer, the first configured printer by default
+	#[serde(default)]
+	pub printer: Option<String>,
+	/// Processes and delivers the document like walk-up scans to this
+	/// destination, the document is returned in the response if unset
+	#[serde(default)]
+	pub destination: Option<String>,
+	#[serde(default = "default_resolution")]
+	pub resolution: u16,
+	#[serde(default)]
+	pub color: Color,
+	#[serde(default)]
+	pub source: Source,
+	#[serde(default)]
+	pub content: Content,
+}
+
+impl Default for ScanRequest {
+	fn default() -> Self {
+		ScanRequest {
+			printer: None,
+			destination: None,
+			resolution: default_resolution(),
+			color: Color::default(),
+			source: Source::default(),
+			content: Content::default(),
+		}
+	}
+}
+
+fn default_resolution() -> u16 {
+	200
+}
+
+/// Answer to scans routed to a destination.
+#[derive(Serialize, Debug)]
+pub struct ScanResponse {
+	pub scan_id: Option<i64>,
+	pub destination: String,
+	pub pages: usize,
+	pub documents: Vec<DocumentSummary>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DocumentSummary {
+	pub filename: String,
+	pub pages: usize,
+	pub size: usize,
+}
+
+pub enum ScanResult {
+	/// The documents were queued for the sinks of the destination
+	Queued(ScanResponse),
+	Document(Document),
+}
+
+/// Runs a scan job requested over HTTP, the same way as scans started on
+/// the printer panel.
+pub fn scan(state: &ServerState, request: &ScanRequest) -> Result<ScanResult, RestError> {
+	if !(75..=1200).contains(&request.resolution) {
+		return Err(RestError::invalid(&format!("Unsupported resolution {}", request.resolution)))
+	}
+
+	let printer = match &request.printer {
+		Some(name) => state.printers.iter()
+			.find(|printer| printer.name == *name)
+			.ok_or_else(|| RestError::invalid(&format!("Unknown printer {}", name)))?,
+		None => state.printers.first()
+			.ok_or_else(|| RestError::unavailable("No printer configured"))?,
+	};
+
+	let destination = match &request.destination {
+		Some(name) => printer.destinations.iter()
+			.find(|destination| destination.name == *name)
+			.cloned()
+			.ok_or_else(|| RestError::invalid(&format!("Unknown destination {} of printer {}", name, printer.name)))?,
+		None => DestinationConfig {
+			name: API_DESTINATION.to_string(),
+			..DestinationConfig::default()
+		},
+	};
+
+	let info = printer.info.lock().unwrap().clone()
+		.ok_or_else(|| RestError::unavailable(&format!("Not connected to printer {} yet", printer.name)))?;
+
+	let span = tracing::info_span!("scan", trigger = "api", printer = %printer.name, destination = %destination.name,
+		scan_id = tracing::field::Empty, job = tracing::field::Empty);
+	let _entered = span.enter();
+
+	// one job at a time, further requests wait for the scanner
+	let scanner = printer.scanner.lock().unwrap();
+	let api = scanner.as_ref()
+		.ok_or_else(|| RestError::unavailable(&format!("Address of printer {} not found yet", printer.name)))?;
+	METRICS.scans_started.with_label_values(&[&printer.name, &destination.name]).inc();
+	let scan_id = state.history.start_scan(API_DESTINATION, &destination.name);
+	if let Some(scan_id) = scan_id {
+		span.record("scan_id", scan_id);
+	}
+
+	let result = request.source.input_source(api).and_then(|source| {
+		state.history.update_settings(scan_id, "Api", source);
+		let job = job_settings(source, request.content.content_type(), request.resolution as i16, request.color.color_space());
+		let job_location = api.create_job(job)?;
+		log::debug!("New scan job created successfully");
+		download_pages(api, &job_location, &destination.name, false)
+	});
+	drop(scanner);
+
+	let pages = match result {
+		Ok(pages) => pages,
+		Err(e) => {
+			METRICS.scans_failed.with_label_values(&[&printer.name, &destination.name]).inc();
+			state.history.finish_scan(scan_id, 0, 0, Err(e.to_string()));
+			return Err(RestError::printer(&e))
+		}
+	};
+
+	let page_count = pages.len();
+	let mut documents = match process(pages, &destination, request.resolution, &info) {
+		Ok(documents) => documents,
+		Err(e) => {
+			METRICS.scans_failed.with_label_values(&[&printer.name, &destination.name]).inc();
+			state.history.finish_scan(scan_id, page_count, 0, Err(e.to_string()));
+			return Err(RestError::new(&format!("Error assembling the document: {}", e), 500))
+		}
+	};
+	for document in documents.iter_mut() {
+		document.scan_id = scan_id;
+	}
+	let size = documents.iter().map(|document| document.content.len()).sum();
+	METRICS.scans_completed.with_label_values(&[&printer.name, &destination.name]).inc();
+	state.history.finish_scan(scan_id, page_count, size, Ok(()));
+
+	if request.destination.is_none() {
+		return documents.into_iter().next()
+			.map(ScanResult::Document)
+			.ok_or_else(|| RestError::printer(&ApiError::new("Processing produced no document")))
+	}
+
+	for document in &documents {
+		submit(document, &destination, &state.outbox, &state.history);
+	}
+
+	Ok(ScanResult::Queued(ScanResponse {
+		scan_id,
+		destination: destination.name.clone(),
+		pages: page_count,
+		documents: documents.iter()
+			.map(|document| DocumentSummary {
+				filename: document.filename.clone(),
+				pages: document.page_count,
+				size: document.content.len(),
+			})
+			.collect(),
+	}))
+}
+
+#[derive(Debug, Clone)]
+pub struct RestError {
+	pub details: String,
+	/// HTTP status code of the answer
+	pub status: u16,
+}
+
+impl RestError {
+	pub fn new(msg: &str, status: u16) -> RestError {
+		RestError{details: msg.to_string(), status}
+	}
+
+	pub fn invalid(msg: &str) -> RestError {
+		RestError::new(msg, 400)
+	}
+
+	fn unavailable(msg: &str) -> RestError {
+		RestError::new(msg, 503)
+	}
+
+	fn printer(e: &ApiError) -> RestError {
+		RestError::new(&format!("Scan failed: {}", e), 502)
+	}
+}
+
+impl fmt::Display for RestError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.details)
+	}
+}
+
+impl std::error::Error for RestError {}
@@ -0,0 +1,98 @@
+use std::{env, fmt, fs};
+use serde::{Deserialize, Deserializer};
+
+/// A credential from the configuration. Given either as a string or as
+/// `{ file = "/run/secrets/..." }`, the value never shows up in `Debug`
+/// output.
+#[derive(Clone, PartialEq, Default)]
+pub struct Secret(String);
+
+impl Secret {
+	pub fn new(value: &str) -> Secret {
+		Secret(value.to_string())
+	}
+
+	/// Reads the file of a Docker or Kubernetes secret, a trailing line
+	/// break is removed.
+	pub fn from_file(path: &str) -> Result<Secret, String> {
+		fs::read_to_string(path)
+			.map(|content| Secret::new(content.trim_end_matches(['\r', '\n'])))
+			.map_err(|e| format!("Error reading secret from {}: {}", path, e))
+	}
+
+	/// Reads the variable or the file named by the variable with `_FILE`
+	/// appended. Unreadable files are logged and treated as unset.
+	pub fn from_env(name: &str) -> Option<Secret> {
+		if let Ok(value) = env::var(name) {
+			return Some(Secret(value));
+		}
+		let path = env::var(format!("{}_FILE", name)).ok()?;
+		match Secret::from_file(&path) {
+			Ok(secret) => Some(secret),
+			Err(e) => {
+				log::error!("{}", e);
+				None
+			}
+		}
+	}
+
+	pub fn expose(&self) -> &str {
+		&self.0
+	}
+}
+
+impl fmt::Debug for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "<redacted>")
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SecretValue {
+	Plain(String),
+	File { file: String },
+}
+
+impl<'de> Deserialize<'de> for Secret {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		match SecretValue::deserialize(deserializer)? {
+			SecretValue::Plain(value) => Ok(Secret(value)),
+			SecretValue::File { file } => Secret::from_file(&file).map_err(serde::de::Error::custom),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde::Deserialize;
+	use super::*;
+
+	#[derive(Deserialize, Debug)]
+	struct Credentials {
+		username: String,
+		password: Secret,
+	}
+
+	#[test]
+	fn redacts_debug_output() {
+		let credentials = toml::from_str::<Credentials>("username = \"scan\"\npassword = \"hunter2\"").unwrap();
+		assert_eq!(credentials.username, "scan");
+		assert_eq!(credentials.password.expose(), "hunter2");
+		let debug = format!("{:?}", credentials);
+		assert!(debug.contains("scan") && debug.contains("<redacted>"));
+		assert!(!debug.contains("hunter2"));
+	}
+
+	#[test]
+	fn reads_secret_files() {
+		let path = env::temp_dir().join(format!("rust-hp-secret-{}", std::process::id()));
+		fs::write(&path, "hunter2\r\n").unwrap();
+		let config = format!("username = \"scan\"\npassword = {{ file = \"{}\" }}", path.display());
+		let credentials = toml::from_str::<Credentials>(&config);
+		fs::remove_file(&path).unwrap();
+
+		assert_eq!(credentials.unwrap().password.expose(), "hunter2");
+		assert!(toml::from_str::<Credentials>("username = \"scan\"\npassword = { file = \"/nonexistent/secret\" }").is_err());
+	}
+}
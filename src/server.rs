@@ -0,0 +1,409 @@
+use std::fmt;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::thread;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use tiny_http::{Header, Method, Request, Response, Server};
+use crate::config::{Config, HttpConfig};
+use crate::health::HEALTH;
+use crate::history::{History, HistoryFilter};
+use crate::metrics::METRICS;
+use crate::objects::ApiError;
+use crate::outbox::Outbox;
+use crate::printer::Printer;
+use crate::rest::{scan, RestError, ScanRequest, ScanResult};
+use crate::web::{dashboard, DestinationStatus, PrinterStatus};
+
+const RECENT_SCANS: usize = 50;
+
+/// Everything the HTTP handlers need, shared with the printer loops.
+pub struct ServerState {
+	pub config: HttpConfig,
+	pub printers: Vec<Arc<Printer>>,
+	pub history: Arc<History>,
+	pub outbox: Arc<Outbox>,
+	pub archive: bool,
+}
+
+impl ServerState {
+	pub fn new(config: &Config, printers: Vec<Arc<Printer>>, history: Arc<History>, outbox: Arc<Outbox>) -> ServerState {
+		ServerState {
+			config: config.http.clone(),
+			printers,
+			history,
+			outbox,
+			archive: config.history.archive.is_some(),
+		}
+	}
+}
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+/// Starts the HTTP server in a background thread if a bind address is
+/// configured.
+pub fn start(state: Arc<ServerState>) -> Result<(), ServerError> {
+	let bind = match &state.config.bind {
+		Some(bind) => bind.clone(),
+		None => return Ok(()),
+	};
+
+	let server = Server::http(&bind)
+		.map_err(|e| ServerError::new(&format!("Error listening on {}: {}", bind, e)))?;
+	log::info!("Listening for HTTP requests on {}", bind);
+
+	thread::spawn(move || {
+		log::debug!("Spawned new thread serving HTTP requests");
+		// scans over HTTP take minutes, health checks and metrics must not
+		// wait for them
+		for request in server.incoming_requests() {
+			let state = Arc::clone(&state);
+			thread::spawn(move || handle(request, &state));
+		}
+	});
+
+	Ok(())
+}
+
+fn handle(mut request: Request, state: &ServerState) {
+	log::debug!("{} {}", request.method(), request.url());
+
+	let method = request.method().clone();
+	let path = request.url().split('?').next().unwrap_or_default().to_string();
+	let segments = path.trim_matches('/').split('/').collect::<Vec<&str>>();
+
+	let response = match (method, segments.as_slice()) {
+		(Method::Get, ["metrics"]) => text(&METRICS.render(), "text/plain; version=0.0.4"),
+		(Method::Get, ["healthz"]) => check(HEALTH.healthy(state.config.heartbeat_timeout_secs)),
+		(Method::Get, ["readyz"]) => check(HEALTH.ready()),
+		(Method::Post, _) if !same_origin(header(&request, "Origin").or(header(&request, "Referer")), header(&request, "Host")) => text("Cross-site request refused\n", "text/plain")
+			.with_status_code(403),
+		_ if !authorized(&request, &state.config) => text("Unauthorized\n", "text/plain")
+			.with_status_code(401)
+			.with_header(Header::from_bytes("WWW-Authenticate", "Basic realm=\"HP Scan to\"").unwrap()),
+		(Method::Get, [""]) => index(state),
+		(Method::Get, ["documents", id]) => download(state, id),
+		(Method::Get, ["documents", id, "thumbnail.jpg"]) => thumbnail(state, id),
+		(Method::Post, ["scans", id, "resend"]) => resend(state, id),
+		(Method::Post, ["api", "scans"]) => api_scan(state, &mut request),
+		_ => not_found(),
+	};
+
+	if let Err(e) = request.respond(response) {
+		log::warn!("Error sending HTTP response: {}", e);
+	}
+}
+
+fn header(request: &Request, name: &'static str) -> Option<String> {
+	request.headers().iter()
+		.find(|header| header.field.equiv(name))
+		.map(|header| header.value.as_str().to_string())
+}
+
+/// Browsers name the page a form was sent from in `Origin` or `Referer`,
+/// forms of other sites must not trigger scans or deliveries. Clients
+/// sending neither are not browsers.
+fn same_origin(source: Option<String>, host: Option<String>) -> bool {
+	let Some(source) = source else { return true };
+	let Some(host) = host else { return false };
+
+	Url::parse(&source).ok()
+		.and_then(|url| Some(match url.port() {
+			Some(port) => format!("{}:{}", url.host_str()?, port),
+			None => url.host_str()?.to_string(),
+		}))
+		.is_some_and(|source| source.eq_ignore_ascii_case(&host))
+}
+
+/// Without configured credentials everything is accessible.
+fn authorized(request: &Request, config: &HttpConfig) -> bool {
+	let (Some(username), Some(password)) = (&config.username, &config.password) else { return true };
+	header(request, "Authorization")
+		.is_some_and(|authorization| valid_credentials(&authorization, username, password.expose()))
+}
+
+/// Compares hashes of the credentials byte by byte without stopping early,
+/// so the response time does not tell how much of a guess was right.
+fn valid_credentials(authorization: &str, username: &str, password: &str) -> bool {
+	let Some(credentials) = authorization.strip_prefix("Basic ")
+		.and_then(|credentials| STANDARD.decode(credentials.trim()).ok()) else { return false };
+
+	let given = Sha256::digest(credentials);
+	let expected = Sha256::digest(format!("{}:{}", username, password));
+	given.iter().zip(expected.iter()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+fn index(state: &ServerState) -> HttpResponse {
+	// printers that are switched off must not delay the others
+	let printers = thread::scope(|scope| {
+		state.printers.iter()
+			.map(|printer| scope.spawn(|| printer_status(printer)))
+			.collect::<Vec<_>>()
+			.into_iter()
+			.map(|handle| handle.join().unwrap())
+			.collect::<Vec<PrinterStatus>>()
+	});
+
+	let filter = HistoryFilter { limit: Some(RECENT_SCANS), ..HistoryFilter::default() };
+	match state.history.scans(&filter) {
+		Ok(scans) => text(&dashboard(&printers, &scans, state.archive), "text/html; charset=utf-8"),
+		Err(e) => error(&format!("Error reading history: {}", e)),
+	}
+}
+
+fn printer_status(printer: &Printer) -> PrinterStatus {
+	let registered = printer.registered.lock().unwrap().clone();
+	let (host, status) = match printer.api.lock().unwrap().as_ref() {
+		Some(api) => (api.host(), api.get_scanner_status()),
+		None => (String::new(), Err(ApiError::new("address not found yet"))),
+	};
+	PrinterStatus {
+		name: printer.name.clone(),
+		host,
+		status,
+		destinations: printer.destinations.iter()
+			.map(|destination| DestinationStatus {
+				name: destination.name.clone(),
+				id: registered.iter()
+					.find(|(_, registered)| *registered == destination.name)
+					.map(|(id, _)| id.to_string()),
+			})
+			.collect(),
+	}
+}
+
+fn download(state: &ServerState, id: &str) -> HttpResponse {
+	let Ok(id) = id.parse::<i64>() else { return not_found() };
+	match state.history.document(id) {
+		Ok(Some(document)) => pdf(document.content, &document.filename),
+		Ok(None) => not_found(),
+		Err(e) => error(&e.to_string()),
+	}
+}
+
+fn thumbnail(state: &ServerState, id: &str) -> HttpResponse {
+	let Ok(id) = id.parse::<i64>() else { return not_found() };
+	match state.history.thumbnail(id) {
+		Some(thumbnail) => Response::from_data(thumbnail)
+			.with_header(Header::from_bytes("Content-Type", "image/jpeg").unwrap()),
+		None => not_found(),
+	}
+}
+
+/// Queues the archived documents of a scan for delivery again.
+fn resend(state: &ServerState, id: &str) -> HttpResponse {
+	let Ok(id) = id.parse::<i64>() else { return not_found() };
+	let scan = match state.history.scan(id) {
+		Ok(Some(scan)) => scan,
+		Ok(None) => return not_found(),
+		Err(e) => return error(&e.to_string()),
+	};
+	if !state.outbox.delivers_to(&scan.destination) {
+		return text(&format!("Scan {} was returned over the API, there are no sinks to send it to\n", scan.id), "text/plain")
+			.with_status_code(409)
+	}
+
+	for record in &scan.documents {
+		let document = match state.history.document(record.id) {
+			Ok(Some(document)) => document,
+			Ok(None) => return not_found(),
+			Err(e) => return error(&e.to_string()),
+		};
+		log::info!("Sending {} again", document.filename);
+		if let Err(e) = state.outbox.enqueue(&document) {
+			return error(&format!("Error queueing {}: {}", document.filename, e));
+		}
+	}
+
+	Response::from_string("")
+		.with_status_code(303)
+		.with_header(Header::from_bytes("Location", "/").unwrap())
+}
+
+/// Starts a scan job, an empty body scans with the default settings.
+fn api_scan(state: &ServerState, request: &mut Request) -> HttpResponse {
+	let mut body = String::new();
+	let scan_request = match request.as_reader().read_to_string(&mut body) {
+		Ok(_) if body.trim().is_empty() => Ok(ScanRequest::default()),
+		Ok(_) => serde_json::from_str::<ScanRequest>(&body)
+			.map_err(|e| RestError::invalid(&format!("Invalid scan request: {}", e))),
+		Err(e) => Err(RestError::invalid(&format!("Error reading request: {}", e))),
+	};
+
+	match scan_request.and_then(|scan_request| scan(state, &scan_request)) {
+		Ok(ScanResult::Queued(response)) => match serde_json::to_string_pretty(&response) {
+			Ok(json) => text(&(json + "\n"), "application/json"),
+			Err(e) => error(&e.to_string()),
+		},
+		Ok(ScanResult::Document(document)) => pdf(document.content, &document.filename),
+		Err(e) => {
+			log::warn!("Scan requested over HTTP failed: {}", e);
+			text(&(serde_json::json!({ "error": e.details }).to_string() + "\n"), "application/json")
+				.with_status_code(e.status)
+		}
+	}
+}
+
+/// Answers with 503 if the check failed, so orchestrators can act on it.
+fn check(result: Result<String, String>) -> HttpResponse {
+	match result {
+		Ok(message) => text(&message, "text/plain"),
+		Err(message) => text(&message, "text/plain").with_status_code(503),
+	}
+}
+
+fn pdf(content: Vec<u8>, filename: &str) -> HttpResponse {
+	let response = Response::from_data(content)
+		.with_header(Header::from_bytes("Content-Type", "application/pdf").unwrap());
+	match Header::from_bytes("Content-Disposition", content_disposition(filename)) {
+		Ok(header) => response.with_header(header),
+		Err(_) => {
+			log::warn!("Could not name the download {}", filename);
+			response
+		}
+	}
+}
+
+/// Header values have to be ASCII. Browsers use the percent encoded UTF-8
+/// name of RFC 6266 and older clients the ASCII fallback.
+fn content_disposition(filename: &str) -> String {
+	let fallback = filename.chars()
+		.map(|c| match c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' {
+			true => c,
+			false => '_',
+		})
+		.collect::<String>();
+	let mut encoded = String::new();
+	for byte in filename.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => encoded.push(byte as char),
+			_ => encoded.push_str(&format!("%{:02X}", byte)),
+		}
+	}
+	format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+fn not_found() -> HttpResponse {
+	text("Not found\n", "text/plain").with_status_code(404)
+}
+
+fn error(message: &str) -> HttpResponse {
+	log::error!("{}", message);
+	text(&format!("{}\n", message), "text/plain").with_status_code(500)
+}
+
+fn text(body: &str, content_type: &str) -> HttpResponse {
+	Response::from_string(body)
+		.with_header(Header::from_bytes("Content-Type", content_type).unwrap())
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerError {
+	pub details: String,
+}
+
+impl ServerError {
+	pub fn new(msg: &str) -> ServerError {
+		ServerError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for ServerError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.details)
+	}
+}
+
+impl std::error::Error for ServerError {}
+
+#[cfg(test)]
+mod tests {
+	use std::{env, fs, process};
+	use chrono::Local;
+	use crate::config::{HistoryConfig, OutboxConfig};
+	use crate::processing::Document;
+	use super::*;
+
+	fn state(directory: &std::path::Path) -> ServerState {
+		let history = Arc::new(History::open(&HistoryConfig {
+			database: ":memory:".to_string(),
+			retention_days: 0,
+			archive: Some(directory.join("archive").display().to_string()),
+		}).unwrap());
+		let outbox = Outbox::start(OutboxConfig {
+			directory: directory.join("outbox").display().to_string(),
+			..OutboxConfig::default()
+		}, &[], Arc::clone(&history)).unwrap();
+		ServerState { config: HttpConfig::default(), printers: Vec::new(), history, outbox, archive: true }
+	}
+
+	#[test]
+	fn downloads_documents_with_unicode_names() {
+		let directory = env::temp_dir().join(format!("rust-hp-server-{}", process::id()));
+		let state = state(&directory);
+		let scan_id = state.history.start_scan("a", "Büro");
+		state.history.archive(&Document {
+			filename: "Scan_Büro.pdf".to_string(),
+			content: b"%PDF-1.4".to_vec(),
+			page_count: 1,
+			destination: "Büro".to_string(),
+			printer: "printer".to_string(),
+			printer_model: String::new(),
+			printer_serial: String::new(),
+			created: Local::now(),
+			scan_id,
+			thumbnail: None,
+		});
+		let id = state.history.scan(scan_id.unwrap()).unwrap().unwrap().documents[0].id;
+
+		let response = download(&state, &id.to_string());
+		fs::remove_dir_all(&directory).unwrap();
+		assert_eq!(response.status_code().0, 200);
+		let disposition = response.headers().iter()
+			.find(|header| header.field.equiv("Content-Disposition"))
+			.unwrap();
+		assert_eq!(disposition.value.as_str(), "attachment; filename=\"Scan_B_ro.pdf\"; filename*=UTF-8''Scan_B%C3%BCro.pdf");
+	}
+
+	fn origin(source: Option<&str>, host: &str) -> bool {
+		same_origin(source.map(str::to_string), Some(host.to_string()))
+	}
+
+	#[test]
+	fn accepts_own_pages() {
+		assert!(origin(Some("http://scanner.local:8080"), "scanner.local:8080"));
+		assert!(origin(Some("http://Scanner.local:8080/"), "scanner.local:8080"));
+		assert!(origin(Some("http://192.168.1.5/scans?page=2"), "192.168.1.5"));
+		assert!(origin(None, "scanner.local:8080"));
+	}
+
+	#[test]
+	fn names_downloads_in_ascii() {
+		assert_eq!(content_disposition("Scan_2024-03-07.pdf"),
+			"attachment; filename=\"Scan_2024-03-07.pdf\"; filename*=UTF-8''Scan_2024-03-07.pdf");
+		assert_eq!(content_disposition("Rechnung Müller \"1\".pdf"),
+			"attachment; filename=\"Rechnung M_ller _1_.pdf\"; filename*=UTF-8''Rechnung%20M%C3%BCller%20%221%22.pdf");
+		assert_eq!(content_disposition("a\r\nb.pdf"), "attachment; filename=\"a__b.pdf\"; filename*=UTF-8''a%0D%0Ab.pdf");
+	}
+
+	#[test]
+	fn refuses_other_sites() {
+		assert!(!origin(Some("https://evil.example"), "scanner.local:8080"));
+		assert!(!origin(Some("http://scanner.local:9090"), "scanner.local:8080"));
+		assert!(!origin(Some("null"), "scanner.local:8080"));
+		assert!(!same_origin(Some("http://scanner.local:8080".to_string()), None));
+	}
+	#[test]
+	fn checks_basic_auth_credentials() {
+		let basic = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+		assert!(valid_credentials(&basic("admin:secret"), "admin", "secret"));
+		assert!(valid_credentials(&basic("admin:with:colon"), "admin", "with:colon"));
+		assert!(!valid_credentials(&basic("admin:secreT"), "admin", "secret"));
+		assert!(!valid_credentials(&basic("admin:secret2"), "admin", "secret"));
+		assert!(!valid_credentials(&basic("admin"), "admin", "secret"));
+		assert!(!valid_credentials("Bearer admin:secret", "admin", "secret"));
+		assert!(!valid_credentials("Basic not base64!", "admin", "secret"));
+	}
+}
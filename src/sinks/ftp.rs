@@ -0,0 +1,309 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use native_tls::{TlsConnector, TlsStream};
+use serde::Deserialize;
+use crate::processing::Document;
+use crate::secret::Secret;
+use crate::sinks::{Sink, SinkError};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FtpConfig {
+	pub host: String,
+	#[serde(default = "default_port")]
+	pub port: u16,
+	#[serde(default = "default_username")]
+	pub username: String,
+	#[serde(default)]
+	pub password: Secret,
+	/// Upgrade the connection with `AUTH TLS` (explicit FTPS)
+	#[serde(default)]
+	pub tls: bool,
+	#[serde(default)]
+	pub directory: String,
+}
+
+fn default_port() -> u16 {
+	21
+}
+
+fn default_username() -> String {
+	"anonymous".to_string()
+}
+
+enum Stream {
+	Plain(TcpStream),
+	Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for Stream {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		match self {
+			Stream::Plain(stream) => stream.read(buf),
+			Stream::Tls(stream) => stream.read(buf),
+		}
+	}
+}
+
+impl Write for Stream {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			Stream::Plain(stream) => stream.write(buf),
+			Stream::Tls(stream) => stream.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			Stream::Plain(stream) => stream.flush(),
+			Stream::Tls(stream) => stream.flush(),
+		}
+	}
+}
+
+/// Minimal FTP client for the control connection.
+struct FtpConnection {
+	stream: Option<Stream>,
+	host: String,
+	tls: Option<TlsConnector>,
+}
+
+impl FtpConnection {
+	fn connect(config: &FtpConfig) -> Result<FtpConnection, SinkError> {
+		let tcp = open_tcp(&config.host, config.port)?;
+		let mut connection = FtpConnection {
+			stream: Some(Stream::Plain(tcp)),
+			host: config.host.clone(),
+			tls: None,
+		};
+		connection.expect(&[220])?;
+
+		if config.tls {
+			connection.command("AUTH TLS", &[234])?;
+			let connector = TlsConnector::new()
+				.map_err(|e| SinkError::new(&format!("Error setting up TLS: {}", e)))?;
+			let tcp = match connection.stream.take() {
+				Some(Stream::Plain(tcp)) => tcp,
+				_ => return Err(SinkError::new("Control connection is already encrypted")),
+			};
+			let stream = connector.connect(&config.host, tcp)
+				.map_err(|e| SinkError::new(&format!("TLS handshake with {} failed: {}", config.host, e)))?;
+			connection.stream = Some(Stream::Tls(Box::new(stream)));
+			connection.tls = Some(connector);
+		}
+
+		// 230 means the server does not need a password
+		if connection.command(&format!("USER {}", config.username), &[230, 331])? == 331 {
+			connection.command(&format!("PASS {}", config.password.expose()), &[230])?;
+		}
+
+		if connection.tls.is_some() {
+			connection.command("PBSZ 0", &[200])?;
+			connection.command("PROT P", &[200])?;
+		}
+
+		connection.command("TYPE I", &[200])?;
+		Ok(connection)
+	}
+
+	fn stream(&mut self) -> &mut Stream {
+		self.stream.as_mut().expect("Control connection is open")
+	}
+
+	fn read_reply(&mut self) -> Result<(u16, String), SinkError> {
+		read_reply(self.stream())
+	}
+
+	fn expect(&mut self, codes: &[u16]) -> Result<u16, SinkError> {
+		let (code, reply) = self.read_reply()?;
+		match codes.contains(&code) {
+			true => Ok(code),
+			false => Err(SinkError::new(&format!("Unexpected FTP reply: {}", reply.trim()))),
+		}
+	}
+
+	fn command(&mut self, command: &str, codes: &[u16]) -> Result<u16, SinkError> {
+		let logged = match command.starts_with("PASS") {
+			true => "PASS ***",
+			false => command,
+		};
+		log::debug!("FTP command: {}", logged);
+		self.stream().write_all(format!("{}\r\n", command).as_bytes())
+			.map_err(|e| SinkError::new(&format!("Error sending FTP command {}: {}", logged, e)))?;
+		self.expect(codes)
+	}
+
+	/// Opens a passive data connection. The address in the reply is ignored
+	/// in favour of the control connection's host, which also works behind NAT.
+	fn data_connection(&mut self) -> Result<Stream, SinkError> {
+		self.stream().write_all(b"PASV\r\n")
+			.map_err(|e| SinkError::new(&format!("Error sending FTP command PASV: {}", e)))?;
+		let (code, reply) = self.read_reply()?;
+		if code != 227 {
+			return Err(SinkError::new(&format!("Unexpected FTP reply: {}", reply.trim())));
+		}
+
+		let tcp = open_tcp(&self.host, passive_port(&reply)?)?;
+		match &self.tls {
+			Some(connector) => connector.connect(&self.host, tcp)
+				.map(|stream| Stream::Tls(Box::new(stream)))
+				.map_err(|e| SinkError::new(&format!("TLS handshake for data connection failed: {}", e))),
+			None => Ok(Stream::Plain(tcp)),
+		}
+	}
+
+	fn store(&mut self, name: &str, content: &[u8]) -> Result<(), SinkError> {
+		let mut data = self.data_connection()?;
+		self.command(&format!("STOR {}", name), &[125, 150])?;
+
+		data.write_all(content)
+			.map_err(|e| SinkError::new(&format!("Error uploading {}: {}", name, e)))?;
+		if let Stream::Tls(stream) = &mut data {
+			let _ = stream.shutdown();
+		}
+		drop(data);
+
+		self.expect(&[226, 250])?;
+		Ok(())
+	}
+}
+
+/// Reads a reply, multi-line replies end with the code followed by a space.
+fn read_reply(stream: &mut impl Read) -> Result<(u16, String), SinkError> {
+	let mut reply = String::new();
+	loop {
+		let mut line = Vec::new();
+		let mut byte = [0u8; 1];
+		while !line.ends_with(b"\r\n") {
+			let read = stream.read(&mut byte)
+				.map_err(|e| SinkError::new(&format!("Error reading FTP reply: {}", e)))?;
+			if read == 0 {
+				return Err(SinkError::new("FTP server closed the connection"));
+			}
+			line.push(byte[0]);
+		}
+		let line = String::from_utf8_lossy(&line).trim_end().to_string();
+		reply.push_str(&line);
+		reply.push('\n');
+
+		if line.len() >= 4 && line.as_bytes()[3] == b' ' {
+			if let Ok(code) = line[..3].parse::<u16>() {
+				return Ok((code, reply));
+			}
+		}
+	}
+}
+
+/// Port of a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)` reply.
+fn passive_port(reply: &str) -> Result<u16, SinkError> {
+	let invalid = || SinkError::new(&format!("Invalid PASV reply: {}", reply.trim()));
+	let numbers = reply.split(['(', ')'])
+		.nth(1)
+		.ok_or_else(invalid)?
+		.split(',')
+		.map(|number| number.trim().parse::<u8>())
+		.collect::<Result<Vec<u8>, _>>()
+		.map_err(|_| invalid())?;
+	match numbers.as_slice() {
+		[_, _, _, _, high, low] => Ok(u16::from(*high) << 8 | u16::from(*low)),
+		_ => Err(invalid()),
+	}
+}
+
+fn open_tcp(host: &str, port: u16) -> Result<TcpStream, SinkError> {
+	let tcp = TcpStream::connect((host, port))
+		.map_err(|e| SinkError::new(&format!("Error connecting to {}:{}: {}", host, port, e)))?;
+	let _ = tcp.set_read_timeout(Some(TIMEOUT));
+	let _ = tcp.set_write_timeout(Some(TIMEOUT));
+	Ok(tcp)
+}
+
+impl FtpConfig {
+	pub fn name(&self) -> String {
+		format!("FTP {}@{}:{}", self.username, self.host, self.directory)
+	}
+}
+
+pub struct FtpSink {
+	config: FtpConfig,
+}
+
+impl FtpSink {
+	pub fn new(config: FtpConfig) -> FtpSink {
+		FtpSink { config }
+	}
+}
+
+impl Sink for FtpSink {
+	fn name(&self) -> String {
+		self.config.name()
+	}
+
+	fn deliver(&self, document: &Document) -> Result<(), SinkError> {
+		let mut connection = FtpConnection::connect(&self.config)?;
+
+		if !self.config.directory.is_empty() {
+			connection.command(&format!("CWD {}", self.config.directory), &[250])?;
+		}
+
+		// renamed once complete, watchers of the directory only see whole files
+		let temporary = format!(".{}.part", document.filename);
+		connection.store(&temporary, &document.content)?;
+		connection.command(&format!("RNFR {}", temporary), &[350])?;
+		connection.command(&format!("RNTO {}", document.filename), &[250])?;
+
+		let _ = connection.command("QUIT", &[221]);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reads_single_line_replies() {
+		let mut stream = &b"220 FTP server ready\r\n331 Password required\r\n"[..];
+		assert_eq!(read_reply(&mut stream).unwrap(), (220, "220 FTP server ready\n".to_string()));
+		assert_eq!(read_reply(&mut stream).unwrap().0, 331);
+	}
+
+	#[test]
+	fn reads_multi_line_replies() {
+		let mut stream = &b"230-Welcome\r\n 230 indented\r\n230-still going\r\n230 Logged in\r\n221 Bye\r\n"[..];
+		let (code, reply) = read_reply(&mut stream).unwrap();
+		assert_eq!(code, 230);
+		assert_eq!(reply, "230-Welcome\n 230 indented\n230-still going\n230 Logged in\n");
+		assert_eq!(read_reply(&mut stream).unwrap().0, 221);
+
+		let mut stream = &b"211-Features:\r\n UTF8\r\n MLST\r\n211 End\r\n"[..];
+		assert_eq!(read_reply(&mut stream).unwrap(), (211, "211-Features:\n UTF8\n MLST\n211 End\n".to_string()));
+	}
+
+	#[test]
+	fn fails_on_closed_connection() {
+		let mut stream = &b"220-Welcome\r\n220 incomplete"[..];
+		assert!(read_reply(&mut stream).is_err());
+	}
+
+	#[test]
+	fn parses_passive_port() {
+		assert_eq!(passive_port("227 Entering Passive Mode (192,168,1,5,195,149).\n").unwrap(), 50069);
+		assert_eq!(passive_port("227 Entering Passive Mode ( 10, 0, 0, 1, 0, 21 )\n").unwrap(), 21);
+	}
+
+	#[test]
+	fn rejects_malformed_passive_replies() {
+		for reply in [
+			"227 Entering Passive Mode (192,168,1,5,300,1)",
+			"227 Entering Passive Mode (192,168,1,5,-1,1)",
+			"227 Entering Passive Mode (192,168,1,5,195)",
+			"227 Entering Passive Mode (192,168,1,5,195,149,1)",
+			"227 Entering Passive Mode",
+		] {
+			assert!(passive_port(reply).is_err(), "{}", reply);
+		}
+	}
+}
@@ -0,0 +1,141 @@
+use std::env;
+use serde::{Deserialize, Deserializer};
+use crate::processing::Document;
+use crate::sinks::SinkError;
+use crate::template::{escape_html, render};
+
+/// Addresses and texts shared by the mail sinks. Subject and bodies are
+/// templates, unset ones use the texts of the configured language.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MailConfig {
+	/// Falls back to `MAIL_FROM`
+	pub from: Option<String>,
+	/// Falls back to the comma separated addresses in `MAIL_TO`
+	#[serde(default, deserialize_with = "one_or_many")]
+	pub to: Vec<String>,
+	#[serde(default, deserialize_with = "one_or_many")]
+	pub cc: Vec<String>,
+	#[serde(default, deserialize_with = "one_or_many")]
+	pub bcc: Vec<String>,
+	/// Falls back to `MAIL_LANGUAGE`
+	pub language: Option<Language>,
+	pub subject: Option<String>,
+	pub html: Option<String>,
+	pub text: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+	#[serde(alias = "english")]
+	En,
+	#[serde(alias = "german")]
+	De,
+}
+
+impl Language {
+	fn from_env() -> Language {
+		match env::var("MAIL_LANGUAGE").unwrap_or_default().to_lowercase().as_str() {
+			"en" | "english" => Language::En,
+			// the original mails were German
+			_ => Language::De,
+		}
+	}
+
+	fn subject(&self) -> &'static str {
+		match self {
+			Language::En => "New scan: {{filename}}",
+			Language::De => "Neuer Scan: {{filename}}",
+		}
+	}
+
+	fn html(&self) -> &'static str {
+		match self {
+			Language::En => "<p>New scan from {{destination}} attached.</p>\n<p>{{filename}}: {{pages}} pages, {{size_human}}<br>Scanned on {{date}} by {{printer_model}}</p>",
+			Language::De => "<p>Neuer Scan von {{destination}} im Anhang.</p>\n<p>{{filename}}: {{pages}} Seiten, {{size_human}}<br>Gescannt am {{date}} von {{printer_model}}</p>",
+		}
+	}
+
+	fn text(&self) -> &'static str {
+		match self {
+			Language::En => "New scan from {{destination}} attached.\n\n{{filename}}: {{pages}} pages, {{size_human}}\nScanned on {{date}} by {{printer_model}}\n",
+			Language::De => "Neuer Scan von {{destination}} im Anhang.\n\n{{filename}}: {{pages}} Seiten, {{size_human}}\nGescannt am {{date}} von {{printer_model}}\n",
+		}
+	}
+}
+
+/// Addresses and rendered texts of a single mail.
+pub struct Mail {
+	pub from: String,
+	pub to: Vec<String>,
+	pub cc: Vec<String>,
+	pub bcc: Vec<String>,
+	pub subject: String,
+	pub html: String,
+	pub text: String,
+}
+
+impl MailConfig {
+	/// Verifies that sender and recipients are configured.
+	pub fn check(&self) -> Result<(), SinkError> {
+		self.sender()?;
+		self.recipients()?;
+		Ok(())
+	}
+
+	fn sender(&self) -> Result<String, SinkError> {
+		match &self.from {
+			Some(from) => Ok(from.clone()),
+			None => env::var("MAIL_FROM")
+				.map_err(|_| SinkError::new("Must supply MAIL_FROM to send mail")),
+		}
+	}
+
+	fn recipients(&self) -> Result<Vec<String>, SinkError> {
+		match self.to.is_empty() {
+			false => Ok(self.to.clone()),
+			true => Ok(env::var("MAIL_TO")
+				.map_err(|_| SinkError::new("Must supply MAIL_TO to send mail"))?
+				.split(',')
+				.map(|address| address.trim().to_string())
+				.filter(|address| !address.is_empty())
+				.collect()),
+		}
+	}
+
+	pub fn compose(&self, document: &Document) -> Result<Mail, SinkError> {
+		let from = self.sender()?;
+		let to = self.recipients()?;
+
+		let language = self.language.unwrap_or_else(Language::from_env);
+		let values = document.template_values();
+		let html_values = values.iter()
+			.map(|(key, value)| (*key, escape_html(value)))
+			.collect();
+
+		Ok(Mail {
+			from,
+			to,
+			cc: self.cc.clone(),
+			bcc: self.bcc.clone(),
+			subject: render(self.subject.as_deref().unwrap_or(language.subject()), &values),
+			html: render(self.html.as_deref().unwrap_or(language.html()), &html_values),
+			text: render(self.text.as_deref().unwrap_or(language.text()), &values),
+		})
+	}
+}
+
+/// Accepts a single address as well as a list of addresses.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum OneOrMany {
+		One(String),
+		Many(Vec<String>),
+	}
+
+	Ok(match OneOrMany::deserialize(deserializer)? {
+		OneOrMany::One(address) => vec![address],
+		OneOrMany::Many(addresses) => addresses,
+	})
+}
@@ -0,0 +1,141 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+use crate::processing::Document;
+use crate::secret::Secret;
+use crate::sinks::{default_chat_message, Sink, SinkError};
+use crate::template::render;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MatrixConfig {
+	/// e.g. `https://matrix.example.com`
+	pub homeserver: String,
+	pub access_token: Secret,
+	/// Room ID like `!abcdef:example.com`
+	pub room_id: String,
+	/// Template for the message sent along with the file
+	#[serde(default = "default_chat_message")]
+	pub message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct UploadResponse {
+	content_uri: String,
+}
+
+impl MatrixConfig {
+	pub fn name(&self) -> String {
+		format!("Matrix room {}", self.room_id)
+	}
+}
+
+pub struct MatrixSink {
+	config: MatrixConfig,
+	client: Client,
+}
+
+impl MatrixSink {
+	pub fn new(config: MatrixConfig) -> MatrixSink {
+		let client = ClientBuilder::new()
+			.timeout(Duration::from_secs(3 * 60))
+			.build()
+			.expect("Error building the Matrix client");
+
+		MatrixSink { config, client }
+	}
+
+	fn url(&self, segments: &[&str]) -> Result<Url, SinkError> {
+		let mut url = Url::parse(&self.config.homeserver)
+			.map_err(|e| SinkError::new(&format!("Invalid homeserver URL {}: {}", self.config.homeserver, e)))?;
+		url.path_segments_mut()
+			.map_err(|_| SinkError::new(&format!("Invalid homeserver URL {}", self.config.homeserver)))?
+			.pop_if_empty()
+			.extend(segments);
+		Ok(url)
+	}
+
+	fn upload(&self, document: &Document) -> Result<String, SinkError> {
+		let mut url = self.url(&["_matrix", "media", "v3", "upload"])?;
+		url.query_pairs_mut().append_pair("filename", &document.filename);
+
+		self.client.post(url)
+			.bearer_auth(self.config.access_token.expose())
+			.header("Content-Type", "application/pdf")
+			.body(document.content.clone())
+			.send()
+			.and_then(|response| response.error_for_status())
+			.and_then(|response| response.json::<UploadResponse>())
+			.map(|response| response.content_uri)
+			.map_err(|e| SinkError::new(&format!("Error uploading to Matrix: {}", e)))
+	}
+
+	fn send(&self, content: serde_json::Value) -> Result<(), SinkError> {
+		let transaction = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_nanos()
+			.to_string();
+		let url = self.url(&["_matrix", "client", "v3", "rooms", &self.config.room_id, "send", "m.room.message", &transaction])?;
+
+		self.client.put(url)
+			.bearer_auth(self.config.access_token.expose())
+			.json(&content)
+			.send()
+			.and_then(|response| response.error_for_status())
+			.map(|_| ())
+			.map_err(|e| SinkError::new(&format!("Error sending Matrix message: {}", e)))
+	}
+}
+
+impl Sink for MatrixSink {
+	fn name(&self) -> String {
+		self.config.name()
+	}
+
+	fn deliver(&self, document: &Document) -> Result<(), SinkError> {
+		let content_uri = self.upload(document)?;
+
+		self.send(json!({
+			"msgtype": "m.text",
+			"body": render(&self.config.message, &document.template_values()),
+		}))?;
+
+		self.send(json!({
+			"msgtype": "m.file",
+			"body": document.filename,
+			"filename": document.filename,
+			"url": content_uri,
+			"info": {
+				"mimetype": "application/pdf",
+				"size": document.content.len(),
+			},
+		}))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sink(homeserver: &str) -> MatrixSink {
+		MatrixSink::new(toml::from_str(&format!(
+			"homeserver = \"{}\"\naccess_token = \"token\"\nroom_id = \"!abc:example.com\"", homeserver)).unwrap())
+	}
+
+	#[test]
+	fn builds_urls() {
+		let url = sink("https://matrix.example.com/").url(&["_matrix", "client", "v3", "rooms", "!abc:example.com", "send"]).unwrap();
+		assert_eq!(url.as_str(), "https://matrix.example.com/_matrix/client/v3/rooms/!abc:example.com/send");
+		let url = sink("https://example.com/matrix").url(&["_matrix", "media", "v3", "upload"]).unwrap();
+		assert_eq!(url.as_str(), "https://example.com/matrix/_matrix/media/v3/upload");
+		assert!(sink("matrix.example.com").url(&["_matrix"]).is_err());
+	}
+
+	#[test]
+	fn escapes_path_segments() {
+		let url = sink("https://matrix.example.com").url(&["rooms", "room/with space"]).unwrap();
+		assert_eq!(url.path(), "/rooms/room%2Fwith%20space");
+	}
+}
@@ -0,0 +1,297 @@
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+use reqwest::blocking::multipart::{Form, Part};
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::Url;
+use serde::Deserialize;
+use crate::processing::Document;
+use crate::secret::Secret;
+use crate::sinks::{endpoint, Sink, SinkError};
+use crate::template::render;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PaperlessConfig {
+	/// Base URL of the Paperless-ngx instance
+	pub url: String,
+	pub token: Secret,
+	/// Template for the document title
+	pub title: Option<String>,
+	/// Correspondent ID
+	pub correspondent: Option<u32>,
+	/// Document type ID
+	pub document_type: Option<u32>,
+	/// Tag IDs
+	#[serde(default)]
+	pub tags: Vec<u32>,
+	/// How long to wait for Paperless to consume the document
+	#[serde(default = "default_consume_timeout")]
+	pub consume_timeout_secs: u64,
+}
+
+fn default_consume_timeout() -> u64 {
+	300
+}
+
+#[derive(Deserialize, Debug)]
+struct Task {
+	status: String,
+	result: Option<String>,
+	related_document: Option<DocumentId>,
+}
+
+/// Current Paperless-ngx versions return the document ID as a number, older
+/// ones as a string.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum DocumentId {
+	Number(u64),
+	Text(String),
+}
+
+impl fmt::Display for DocumentId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			DocumentId::Number(id) => write!(f, "{}", id),
+			DocumentId::Text(id) => write!(f, "{}", id),
+		}
+	}
+}
+
+impl PaperlessConfig {
+	pub fn name(&self) -> String {
+		format!("Paperless at {}", endpoint(&self.url))
+	}
+}
+
+pub struct PaperlessSink {
+	config: PaperlessConfig,
+	client: Client,
+	poll_interval: Duration,
+}
+
+impl PaperlessSink {
+	pub fn new(config: PaperlessConfig) -> PaperlessSink {
+		let client = ClientBuilder::new()
+			.timeout(Duration::from_secs(3 * 60))
+			.build()
+			.expect("Error building the Paperless client");
+
+		PaperlessSink { config, client, poll_interval: POLL_INTERVAL }
+	}
+
+	fn url(&self, path: &str) -> Result<Url, SinkError> {
+		// without a trailing slash joining would replace the last path segment
+		let base = match self.config.url.ends_with('/') {
+			true => self.config.url.clone(),
+			false => format!("{}/", self.config.url),
+		};
+		Url::parse(&base)
+			.and_then(|base| base.join(path))
+			.map_err(|e| SinkError::new(&format!("Invalid Paperless URL {}: {}", self.config.url, e)))
+	}
+
+	fn upload(&self, document: &Document) -> Result<String, SinkError> {
+		let file = Part::bytes(document.content.clone())
+			.file_name(document.filename.clone())
+			.mime_str("application/pdf")
+			.expect("application/pdf is a valid mime type");
+
+		let mut form = Form::new()
+			.part("document", file);
+
+		if let Some(title) = &self.config.title {
+			form = form.text("title", render(title, &document.template_values()));
+		}
+		if let Some(correspondent) = self.config.correspondent {
+			form = form.text("correspondent", correspondent.to_string());
+		}
+		if let Some(document_type) = self.config.document_type {
+			form = form.text("document_type", document_type.to_string());
+		}
+		for tag in &self.config.tags {
+			form = form.text("tags", tag.to_string());
+		}
+
+		let response = self.client.post(self.url("api/documents/post_document/")?)
+			.header("Authorization", format!("Token {}", self.config.token.expose()))
+			.multipart(form)
+			.send()
+			.map_err(|e| SinkError::new(&format!("Error uploading to Paperless: {}", e)))?;
+
+		if !response.status().is_success() {
+			return Err(SinkError::new(&format!("Paperless returned status {}", response.status())));
+		}
+
+		// the response body is the task ID as a JSON string
+		response.json::<String>()
+			.map_err(|e| SinkError::new(&format!("Paperless did not return a task ID: {}", e)))
+	}
+
+	fn wait_for_task(&self, task_id: &str) -> Result<(), SinkError> {
+		let deadline = Instant::now() + Duration::from_secs(self.config.consume_timeout_secs);
+		let mut url = self.url("api/tasks/")?;
+		url.query_pairs_mut().append_pair("task_id", task_id);
+
+		while Instant::now() < deadline {
+			let tasks = self.client.get(url.clone())
+				.header("Authorization", format!("Token {}", self.config.token.expose()))
+				.send()
+				.and_then(|response| response.error_for_status())
+				.and_then(|response| response.json::<Vec<Task>>())
+				.map_err(|e| SinkError::new(&format!("Error reading Paperless task {}: {}", task_id, e)))?;
+
+			if let Some(task) = tasks.first() {
+				match task.status.as_str() {
+					"SUCCESS" => {
+						log::debug!("Paperless consumed the document as document {}", task.related_document.as_ref().map(DocumentId::to_string).unwrap_or("unknown".to_string()));
+						return Ok(());
+					}
+					"FAILURE" | "REVOKED" => {
+						return Err(SinkError::new(&format!("Paperless failed to consume the document: {}",
+							task.result.as_deref().unwrap_or("no details"))));
+					}
+					_ => {}
+				}
+			}
+
+			thread::sleep(self.poll_interval);
+		}
+
+		Err(SinkError::new(&format!("Paperless did not consume the document within {} seconds", self.config.consume_timeout_secs)))
+	}
+}
+
+impl Sink for PaperlessSink {
+	fn name(&self) -> String {
+		self.config.name()
+	}
+
+	fn deliver(&self, document: &Document) -> Result<(), SinkError> {
+		let task_id = self.upload(document)?;
+		log::debug!("Paperless accepted {} with task {}", document.filename, task_id);
+		self.wait_for_task(&task_id)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reads_document_ids() {
+		let tasks = serde_json::from_str::<Vec<Task>>(r#"[
+			{"status": "SUCCESS", "result": "Success. New document id 42 created", "related_document": 42},
+			{"status": "SUCCESS", "result": null, "related_document": "17"},
+			{"status": "STARTED", "result": null, "related_document": null}
+		]"#).unwrap();
+		assert_eq!(tasks[0].related_document, Some(DocumentId::Number(42)));
+		assert_eq!(tasks[1].related_document.as_ref().map(DocumentId::to_string).as_deref(), Some("17"));
+		assert_eq!(tasks[2].related_document, None);
+	}
+	struct Received {
+		/// Method and URL
+		line: String,
+		authorization: String,
+		body: String,
+	}
+
+	/// Answers the requests in order with the given status and body and
+	/// returns the requests it received.
+	fn stub(responses: &[(u16, &'static str)]) -> (String, thread::JoinHandle<Vec<Received>>) {
+		let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/paperless", server.server_addr().to_ip().unwrap());
+		let responses = responses.to_vec();
+		let handle = thread::spawn(move || {
+			let mut requests = Vec::new();
+			for (status, body) in responses {
+				let Ok(Some(mut request)) = server.recv_timeout(Duration::from_secs(5)) else { break };
+				let authorization = request.headers().iter()
+					.find(|header| header.field.equiv("Authorization"))
+					.map(|header| header.value.to_string())
+					.unwrap_or_default();
+				let mut content = String::new();
+				request.as_reader().read_to_string(&mut content).unwrap();
+				requests.push(Received { line: format!("{} {}", request.method(), request.url()), authorization, body: content });
+				request.respond(tiny_http::Response::from_string(body).with_status_code(status)).unwrap();
+			}
+			requests
+		});
+		(url, handle)
+	}
+
+	fn sink(url: &str, timeout_secs: u64) -> PaperlessSink {
+		let mut sink = PaperlessSink::new(toml::from_str(&format!(
+			"url = \"{}\"\ntoken = \"secret\"\ntitle = \"Scan of {{{{destination}}}}\"\ntags = [1, 2]\nconsume_timeout_secs = {}",
+			url, timeout_secs)).unwrap());
+		sink.poll_interval = Duration::from_millis(50);
+		sink
+	}
+
+	fn document() -> Document {
+		Document {
+			filename: "scan.pdf".to_string(),
+			content: b"%PDF-1.4".to_vec(),
+			page_count: 1,
+			destination: "Office".to_string(),
+			printer: "printer".to_string(),
+			printer_model: String::new(),
+			printer_serial: String::new(),
+			created: chrono::Local::now(),
+			scan_id: None,
+			thumbnail: None,
+		}
+	}
+
+	#[test]
+	fn uploads_and_waits_for_consumption() {
+		let (url, server) = stub(&[
+			(200, r#""task-1""#),
+			(200, r#"[{"status": "STARTED", "result": null, "related_document": null}]"#),
+			(200, r#"[{"status": "SUCCESS", "result": "Success", "related_document": 42}]"#),
+		]);
+		sink(&url, 10).deliver(&document()).unwrap();
+
+		let requests = server.join().unwrap();
+		assert_eq!(requests.len(), 3);
+		let Received { line, authorization, body } = &requests[0];
+		assert_eq!(line, "POST /paperless/api/documents/post_document/");
+		assert_eq!(authorization, "Token secret");
+		assert!(body.contains("name=\"title\"\r\n\r\nScan of Office\r\n"), "{}", body);
+		assert_eq!(body.matches("name=\"tags\"").count(), 2);
+		assert!(body.contains("filename=\"scan.pdf\""));
+		assert_eq!(requests[1].line, "GET /paperless/api/tasks/?task_id=task-1");
+		assert_eq!(requests[2].line, "GET /paperless/api/tasks/?task_id=task-1");
+	}
+
+	#[test]
+	fn reports_failed_tasks() {
+		let (url, server) = stub(&[
+			(200, r#""task-2""#),
+			(200, r#"[{"status": "FAILURE", "result": "scan.pdf: Not consuming scan.pdf: It is a duplicate", "related_document": null}]"#),
+		]);
+		let error = sink(&url, 10).deliver(&document()).unwrap_err();
+		server.join().unwrap();
+		assert_eq!(error.details, "Paperless failed to consume the document: scan.pdf: Not consuming scan.pdf: It is a duplicate");
+	}
+
+	#[test]
+	fn gives_up_after_consume_timeout() {
+		let mut responses = vec![(200, r#""task-3""#)];
+		responses.extend([(200, r#"[]"#); 100]);
+		let (url, server) = stub(&responses);
+		let error = sink(&url, 1).deliver(&document()).unwrap_err();
+		assert_eq!(error.details, "Paperless did not consume the document within 1 seconds");
+		drop(server);
+	}
+
+	#[test]
+	fn reports_rejected_uploads() {
+		let (url, server) = stub(&[(401, r#"{"detail": "Invalid token."}"#)]);
+		let error = sink(&url, 10).deliver(&document()).unwrap_err();
+		server.join().unwrap();
+		assert_eq!(error.details, "Paperless returned status 401 Unauthorized");
+	}
+}
@@ -0,0 +1,102 @@
+use sendgrid::v3::{Attachment, Content, Email, Message, Personalization, Sender};
+use serde::Deserialize;
+use crate::processing::Document;
+use crate::secret::Secret;
+use crate::sinks::mail::MailConfig;
+use crate::sinks::{Sink, SinkError};
+
+/// An unset API key is read from the `SENDGRID_API_KEY` environment variable
+/// or the file named by `SENDGRID_API_KEY_FILE`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SendgridConfig {
+	pub api_key: Option<Secret>,
+	#[serde(flatten)]
+	pub mail: MailConfig,
+}
+
+impl SendgridConfig {
+	pub fn name(&self) -> String {
+		"SendGrid".to_string()
+	}
+}
+
+pub struct SendgridSink {
+	config: SendgridConfig,
+}
+
+impl SendgridSink {
+	pub fn new(config: SendgridConfig) -> SendgridSink {
+		SendgridSink { config }
+	}
+
+	fn api_key(&self) -> Result<Secret, SinkError> {
+		self.config.api_key.clone()
+			.or_else(|| Secret::from_env("SENDGRID_API_KEY"))
+			.ok_or_else(|| SinkError::new("Must supply SENDGRID_API_KEY or SENDGRID_API_KEY_FILE to send mail"))
+	}
+}
+
+impl Sink for SendgridSink {
+	fn name(&self) -> String {
+		self.config.name()
+	}
+
+	fn check(&self) -> Result<(), SinkError> {
+		self.api_key()?;
+		self.config.mail.check()
+	}
+
+	fn deliver(&self, document: &Document) -> Result<(), SinkError> {
+		let api_key = self.api_key()?;
+		let mail = self.config.mail.compose(document)?;
+
+		log::debug!("Sending mail with {} pages to {} recipients...", document.page_count,
+			mail.to.len() + mail.cc.len() + mail.bcc.len());
+
+		let mut to = mail.to.iter();
+		let first = to.next()
+			.ok_or_else(|| SinkError::new("Mail has no recipients"))?;
+		let mut p = Personalization::new(Email::new(first));
+		for address in to {
+			p = p.add_to(Email::new(address));
+		}
+		for address in &mail.cc {
+			p = p.add_cc(Email::new(address));
+		}
+		for address in &mail.bcc {
+			p = p.add_bcc(Email::new(address));
+		}
+
+		let attachment = Attachment::new()
+			.set_filename(&document.filename)
+			.set_mime_type("application/pdf")
+			.set_content(&document.content);
+
+		// SendGrid expects the plain text part before the HTML part
+		let m = Message::new(Email::new(mail.from))
+			.set_subject(&mail.subject)
+			.add_content(
+				Content::new()
+					.set_content_type("text/plain")
+					.set_value(mail.text),
+			)
+			.add_content(
+				Content::new()
+					.set_content_type("text/html")
+					.set_value(mail.html),
+			)
+			.add_attachment(attachment)
+			.add_personalization(p);
+
+		let sender = Sender::new(api_key.expose().to_string());
+		let response = sender.send(&m)
+			.map_err(|e| SinkError::new(&format!("Error sending mail: {}", e)))?;
+
+		log::info!("Sendgrid Status code: {}", response.status());
+
+		match response.status().is_success() {
+			true => Ok(()),
+			false => Err(SinkError::new(&format!("Sendgrid returned status {}", response.status()))),
+		}
+	}
+}
@@ -0,0 +1,279 @@
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::Local;
+use serde::Deserialize;
+use wait_timeout::ChildExt;
+use crate::processing::Document;
+use crate::sinks::mail::{Mail, MailConfig};
+use crate::sinks::{Sink, SinkError};
+
+/// Pipes a MIME message to a sendmail compatible program like msmtp. The
+/// recipients are passed as arguments, so Bcc addresses stay hidden.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SendmailConfig {
+	#[serde(default = "default_command")]
+	pub command: String,
+	/// Arguments put before the sender and recipients
+	#[serde(default = "default_args")]
+	pub args: Vec<String>,
+	#[serde(default = "default_timeout")]
+	pub timeout_secs: u64,
+	#[serde(flatten)]
+	pub mail: MailConfig,
+}
+
+fn default_command() -> String {
+	"/usr/sbin/sendmail".to_string()
+}
+
+fn default_args() -> Vec<String> {
+	vec!["-i".to_string()]
+}
+
+fn default_timeout() -> u64 {
+	60
+}
+
+impl SendmailConfig {
+	pub fn name(&self) -> String {
+		format!("sendmail {}", self.command)
+	}
+}
+
+pub struct SendmailSink {
+	config: SendmailConfig,
+}
+
+impl SendmailSink {
+	pub fn new(config: SendmailConfig) -> SendmailSink {
+		SendmailSink { config }
+	}
+}
+
+/// Encodes header values containing non-ASCII characters as RFC 2047 words.
+fn encode_header(value: &str) -> String {
+	match value.is_ascii() {
+		true => value.to_string(),
+		false => format!("=?UTF-8?B?{}?=", STANDARD.encode(value)),
+	}
+}
+
+/// Strips the display name from addresses like `Scanner <scan@example.com>`.
+fn bare_address(address: &str) -> &str {
+	match (address.rfind('<'), address.rfind('>')) {
+		(Some(start), Some(end)) if start < end => &address[start + 1..end],
+		_ => address.trim(),
+	}
+}
+
+/// Base64 with the line length limit of RFC 2045.
+fn encode_body(data: &[u8]) -> String {
+	let encoded = STANDARD.encode(data);
+	let mut wrapped = String::with_capacity(encoded.len() + encoded.len() / 38);
+	for line in encoded.as_bytes().chunks(76) {
+		wrapped.push_str(std::str::from_utf8(line).unwrap());
+		wrapped.push_str("\r\n");
+	}
+	wrapped
+}
+
+/// Builds a multipart/mixed message with the text and HTML alternatives and
+/// the document attached.
+fn build_message(mail: &Mail, document: &Document) -> Vec<u8> {
+	let now = Local::now();
+	let unique = format!("{}.{}", now.timestamp_nanos_opt().unwrap_or_default(), std::process::id());
+	let domain = bare_address(&mail.from).rsplit_once('@')
+		.map(|(_, domain)| domain)
+		.unwrap_or("localhost");
+	let mixed = format!("mixed-{}", unique);
+	let alternative = format!("alternative-{}", unique);
+
+	let mut message = String::new();
+	message.push_str(&format!("From: {}\r\n", mail.from));
+	message.push_str(&format!("To: {}\r\n", mail.to.join(", ")));
+	if !mail.cc.is_empty() {
+		message.push_str(&format!("Cc: {}\r\n", mail.cc.join(", ")));
+	}
+	message.push_str(&format!("Subject: {}\r\n", encode_header(&mail.subject)));
+	message.push_str(&format!("Date: {}\r\n", now.to_rfc2822()));
+	message.push_str(&format!("Message-ID: <{}@{}>\r\n", unique, domain));
+	message.push_str("MIME-Version: 1.0\r\n");
+	message.push_str(&format!("Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n", mixed));
+
+	message.push_str(&format!("--{}\r\n", mixed));
+	message.push_str(&format!("Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n", alternative));
+	for (content_type, body) in [("text/plain", &mail.text), ("text/html", &mail.html)] {
+		message.push_str(&format!("--{}\r\n", alternative));
+		message.push_str(&format!("Content-Type: {}; charset=utf-8\r\n", content_type));
+		message.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+		message.push_str(&encode_body(body.as_bytes()));
+	}
+	message.push_str(&format!("--{}--\r\n", alternative));
+
+	let filename = encode_header(&document.filename).replace('"', "");
+	message.push_str(&format!("--{}\r\n", mixed));
+	message.push_str(&format!("Content-Type: application/pdf; name=\"{}\"\r\n", filename));
+	message.push_str(&format!("Content-Disposition: attachment; filename=\"{}\"\r\n", filename));
+	message.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+	message.push_str(&encode_body(&document.content));
+	message.push_str(&format!("--{}--\r\n", mixed));
+
+	message.into_bytes()
+}
+
+/// Kills the child and reaps it. The writer is left to fail once nothing
+/// holds the input open anymore, forked helpers of sendmail may keep it.
+fn stop(child: &mut Child) {
+	let _ = child.kill();
+	let _ = child.wait();
+}
+
+impl Sink for SendmailSink {
+	fn name(&self) -> String {
+		self.config.name()
+	}
+
+	fn check(&self) -> Result<(), SinkError> {
+		self.config.mail.check()
+	}
+
+	fn deliver(&self, document: &Document) -> Result<(), SinkError> {
+		let mail = self.config.mail.compose(document)?;
+		let message = build_message(&mail, document);
+
+		let recipients = mail.to.iter()
+			.chain(mail.cc.iter())
+			.chain(mail.bcc.iter())
+			.map(|address| bare_address(address));
+
+		let mut child = Command::new(&self.config.command)
+			.args(&self.config.args)
+			.arg("-f")
+			.arg(bare_address(&mail.from))
+			.arg("--")
+			.args(recipients)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::null())
+			.stderr(Stdio::piped())
+			.spawn()
+			.map_err(|e| SinkError::new(&format!("Error starting {}: {}", self.config.command, e)))?;
+
+		// written in a separate thread, a sendmail that stops reading must
+		// not block past the timeout
+		let stdin = child.stdin.take();
+		let writer = thread::spawn(move || match stdin {
+			Some(mut stdin) => stdin.write_all(&message),
+			None => Ok(()),
+		});
+
+		let status = match child.wait_timeout(Duration::from_secs(self.config.timeout_secs)) {
+			Ok(Some(status)) => status,
+			Ok(None) => {
+				stop(&mut child);
+				return Err(SinkError::new(&format!("{} did not finish within {} seconds", self.config.command, self.config.timeout_secs)))
+			}
+			Err(e) => {
+				stop(&mut child);
+				return Err(SinkError::new(&format!("Error waiting for {}: {}", self.config.command, e)))
+			}
+		};
+		let written = writer.join()
+			.unwrap_or_else(|_| Err(io::Error::other("writer panicked")));
+
+		if !status.success() {
+			let mut error = String::new();
+			if let Some(mut stderr) = child.stderr.take() {
+				let _ = stderr.read_to_string(&mut error);
+			}
+			return Err(SinkError::new(&format!("{} exited with {}: {}", self.config.command, status, error.trim())))
+		}
+		written.map_err(|e| SinkError::new(&format!("Error writing to {}: {}", self.config.command, e)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+	use super::*;
+
+	fn document(content: Vec<u8>) -> Document {
+		Document {
+			filename: "Scan_ä.pdf".to_string(),
+			content,
+			page_count: 1,
+			destination: "Scan".to_string(),
+			printer: "printer".to_string(),
+			printer_model: "HP OfficeJet".to_string(),
+			printer_serial: "CN123".to_string(),
+			created: Local::now(),
+			scan_id: None,
+			thumbnail: None,
+		}
+	}
+
+	fn mail() -> Mail {
+		Mail {
+			from: "Scanner <scan@example.com>".to_string(),
+			to: vec!["a@example.com".to_string(), "b@example.com".to_string()],
+			cc: Vec::new(),
+			bcc: vec!["hidden@example.com".to_string()],
+			subject: "Neuer Scan: Scan_ä.pdf".to_string(),
+			html: "<p>Scan</p>".to_string(),
+			text: "Scan".to_string(),
+		}
+	}
+
+	fn sink(script: &str, timeout_secs: u64) -> SendmailSink {
+		SendmailSink::new(SendmailConfig {
+			command: "/bin/sh".to_string(),
+			args: vec!["-c".to_string(), script.to_string(), "sh".to_string()],
+			timeout_secs,
+			mail: MailConfig {
+				from: Some("scan@example.com".to_string()),
+				to: vec!["a@example.com".to_string()],
+				..MailConfig::default()
+			},
+		})
+	}
+
+	#[test]
+	fn builds_message() {
+		let content = (0..=255).collect::<Vec<u8>>();
+		let message = String::from_utf8(build_message(&mail(), &document(content.clone()))).unwrap();
+		let (headers, _) = message.split_once("\r\n\r\n").unwrap();
+
+		assert!(headers.contains("From: Scanner <scan@example.com>\r\n"));
+		assert!(headers.contains("To: a@example.com, b@example.com\r\n"));
+		assert!(!message.contains("hidden@example.com"));
+		assert!(headers.contains(&format!("Subject: =?UTF-8?B?{}?=\r\n", STANDARD.encode("Neuer Scan: Scan_ä.pdf"))));
+
+		let attachment = message.rsplit("Content-Transfer-Encoding: base64\r\n\r\n").next().unwrap();
+		let encoded = attachment.split("--").next().unwrap();
+		assert!(encoded.lines().all(|line| line.len() <= 76));
+		assert_eq!(STANDARD.decode(encoded.replace("\r\n", "")).unwrap(), content);
+	}
+
+	#[test]
+	fn strips_display_name() {
+		assert_eq!(bare_address("Scanner <scan@example.com>"), "scan@example.com");
+		assert_eq!(bare_address(" scan@example.com "), "scan@example.com");
+	}
+
+	#[test]
+	fn times_out_when_not_reading() {
+		let started = Instant::now();
+		let result = sink("sleep 30", 1).deliver(&document(vec![0; 1 << 20]));
+		assert!(result.unwrap_err().details.contains("did not finish"));
+		assert!(started.elapsed() < Duration::from_secs(10));
+	}
+
+	#[test]
+	fn reports_exit_status_before_write_error() {
+		let result = sink("echo rejected >&2; exit 1", 10).deliver(&document(vec![0; 1 << 20]));
+		assert!(result.unwrap_err().details.contains("rejected"));
+	}
+}
@@ -0,0 +1,164 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::Path;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use serde::Deserialize;
+use ssh2::{CheckResult, HashType, KnownHostFileKind, RenameFlags, Session};
+use crate::processing::Document;
+use crate::secret::Secret;
+use crate::sinks::{Sink, SinkError};
+
+const TIMEOUT_MS: u32 = 60 * 1000;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SftpConfig {
+	pub host: String,
+	#[serde(default = "default_port")]
+	pub port: u16,
+	pub username: String,
+	pub password: Option<Secret>,
+	/// Path of a private key, used instead of the password
+	pub private_key: Option<String>,
+	pub passphrase: Option<Secret>,
+	/// OpenSSH known_hosts file to verify the server's host key against
+	pub known_hosts: Option<String>,
+	/// Pinned host key as printed by `ssh-keygen -lf`, like `SHA256:...`
+	pub host_key_fingerprint: Option<String>,
+	#[serde(default = "default_directory")]
+	pub directory: String,
+}
+
+fn default_port() -> u16 {
+	22
+}
+
+fn default_directory() -> String {
+	".".to_string()
+}
+
+impl SftpConfig {
+	pub fn name(&self) -> String {
+		format!("SFTP {}@{}:{}", self.username, self.host, self.directory)
+	}
+
+	/// The host key is always verified, either with known_hosts or a pinned
+	/// fingerprint.
+	pub fn validate(&self) -> Result<(), SinkError> {
+		match (&self.known_hosts, &self.host_key_fingerprint) {
+			(None, None) => Err(SinkError::new(&format!("known_hosts or host_key_fingerprint must be set to verify {}", self.host))),
+			(_, Some(fingerprint)) if !fingerprint.starts_with("SHA256:") => Err(SinkError::new("host_key_fingerprint must start with SHA256:")),
+			_ => Ok(()),
+		}
+	}
+}
+
+pub struct SftpSink {
+	config: SftpConfig,
+}
+
+impl SftpSink {
+	pub fn new(config: SftpConfig) -> SftpSink {
+		SftpSink { config }
+	}
+
+	fn connect(&self) -> Result<Session, SinkError> {
+		let error = |e: ssh2::Error| SinkError::new(&format!("SSH error with {}: {}", self.config.host, e));
+
+		let tcp = TcpStream::connect((self.config.host.as_str(), self.config.port))
+			.map_err(|e| SinkError::new(&format!("Error connecting to {}: {}", self.config.host, e)))?;
+
+		let mut session = Session::new().map_err(error)?;
+		session.set_tcp_stream(tcp);
+		session.set_timeout(TIMEOUT_MS);
+		session.handshake().map_err(error)?;
+
+		self.config.validate()?;
+		if let Some(known_hosts_file) = &self.config.known_hosts {
+			let mut known_hosts = session.known_hosts().map_err(error)?;
+			known_hosts.read_file(Path::new(known_hosts_file), KnownHostFileKind::OpenSSH)
+				.map_err(error)?;
+			let (key, _) = session.host_key()
+				.ok_or(SinkError::new(&format!("{} did not send a host key", self.config.host)))?;
+			match known_hosts.check_port(&self.config.host, self.config.port, key) {
+				CheckResult::Match => {}
+				result => return Err(SinkError::new(&format!("Host key verification for {} failed: {:?}", self.config.host, result))),
+			}
+		}
+		if let Some(fingerprint) = &self.config.host_key_fingerprint {
+			let hash = session.host_key_hash(HashType::Sha256)
+				.ok_or(SinkError::new(&format!("{} did not send a host key", self.config.host)))?;
+			let actual = format!("SHA256:{}", STANDARD_NO_PAD.encode(hash));
+			if actual != fingerprint.trim_end_matches('=') {
+				return Err(SinkError::new(&format!("Host key of {} is {}, expected {}", self.config.host, actual, fingerprint)));
+			}
+		}
+
+		match &self.config.private_key {
+			Some(private_key) => session.userauth_pubkey_file(
+				&self.config.username, None, Path::new(private_key), self.config.passphrase.as_ref().map(Secret::expose)),
+			None => session.userauth_password(
+				&self.config.username, self.config.password.as_ref().map(Secret::expose).unwrap_or_default()),
+		}.map_err(error)?;
+
+		Ok(session)
+	}
+}
+
+impl Sink for SftpSink {
+	fn name(&self) -> String {
+		self.config.name()
+	}
+
+	fn check(&self) -> Result<(), SinkError> {
+		self.config.validate()
+	}
+
+	/// Uploads to a hidden temporary name first and renames the file
+	/// afterwards so consumers never pick up partial files.
+	fn deliver(&self, document: &Document) -> Result<(), SinkError> {
+		let session = self.connect()?;
+		let sftp = session.sftp()
+			.map_err(|e| SinkError::new(&format!("Error starting SFTP session: {}", e)))?;
+
+		let directory = Path::new(&self.config.directory);
+		let temporary = directory.join(format!(".{}.part", document.filename));
+		let target = directory.join(&document.filename);
+
+		let mut file = sftp.create(&temporary)
+			.map_err(|e| SinkError::new(&format!("Error creating {}: {}", temporary.display(), e)))?;
+		file.write_all(&document.content)
+			.map_err(|e| SinkError::new(&format!("Error writing {}: {}", temporary.display(), e)))?;
+		drop(file);
+
+		sftp.rename(&temporary, &target, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE))
+			.map_err(|e| SinkError::new(&format!("Error renaming {} to {}: {}", temporary.display(), target.display(), e)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(known_hosts: Option<&str>, fingerprint: Option<&str>) -> SftpConfig {
+		SftpConfig {
+			host: "dms.local".to_string(),
+			port: default_port(),
+			username: "scanner".to_string(),
+			password: None,
+			private_key: None,
+			passphrase: None,
+			known_hosts: known_hosts.map(str::to_string),
+			host_key_fingerprint: fingerprint.map(str::to_string),
+			directory: default_directory(),
+		}
+	}
+
+	#[test]
+	fn requires_host_key_verification() {
+		assert!(config(None, None).validate().is_err());
+		assert!(config(Some("/keys/known_hosts"), None).validate().is_ok());
+		assert!(config(None, Some("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s")).validate().is_ok());
+		assert!(config(None, Some("uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s")).validate().is_err());
+	}
+}
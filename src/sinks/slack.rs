@@ -0,0 +1,136 @@
+use std::time::Duration;
+use reqwest::blocking::{Client, ClientBuilder};
+use serde::Deserialize;
+use serde_json::json;
+use crate::processing::Document;
+use crate::secret::Secret;
+use crate::sinks::{default_chat_message, Sink, SinkError};
+use crate::template::render;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SlackConfig {
+	/// Bot token with the `files:write` scope
+	pub token: Secret,
+	/// Channel ID like `C0123456789`
+	pub channel: String,
+	/// Template for the comment posted with the file
+	#[serde(default = "default_chat_message")]
+	pub message: String,
+	/// Base URL of the Web API, can point to a Slack compatible server
+	#[serde(default = "default_api_url")]
+	pub api_url: String,
+}
+
+fn default_api_url() -> String {
+	"https://slack.com/api".to_string()
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiResponse {
+	ok: bool,
+	error: Option<String>,
+	upload_url: Option<String>,
+	file_id: Option<String>,
+}
+
+impl ApiResponse {
+	fn check(self, method: &str) -> Result<ApiResponse, SinkError> {
+		match self.ok {
+			true => Ok(self),
+			false => Err(SinkError::new(&format!("Slack {} failed: {}", method, self.error.unwrap_or_default()))),
+		}
+	}
+}
+
+impl SlackConfig {
+	pub fn name(&self) -> String {
+		format!("Slack channel {}", self.channel)
+	}
+}
+
+pub struct SlackSink {
+	config: SlackConfig,
+	client: Client,
+}
+
+impl SlackSink {
+	pub fn new(config: SlackConfig) -> SlackSink {
+		let client = ClientBuilder::new()
+			.timeout(Duration::from_secs(3 * 60))
+			.build()
+			.expect("Error building the Slack client");
+
+		SlackSink { config, client }
+	}
+
+	fn method_url(&self, method: &str) -> String {
+		format!("{}/{}", self.config.api_url.trim_end_matches('/'), method)
+	}
+}
+
+impl Sink for SlackSink {
+	fn name(&self) -> String {
+		self.config.name()
+	}
+
+	/// Uses the external upload flow: reserve an upload URL, upload the file
+	/// and share it in the channel.
+	fn deliver(&self, document: &Document) -> Result<(), SinkError> {
+		let error = |e: reqwest::Error| SinkError::new(&format!("Error sending to Slack: {}", e));
+
+		let length = document.content.len().to_string();
+		let reserved = self.client.post(self.method_url("files.getUploadURLExternal"))
+			.bearer_auth(self.config.token.expose())
+			.form(&[("filename", document.filename.as_str()), ("length", length.as_str())])
+			.send()
+			.and_then(|response| response.json::<ApiResponse>())
+			.map_err(error)?
+			.check("files.getUploadURLExternal")?;
+
+		let (upload_url, file_id) = match (reserved.upload_url, reserved.file_id) {
+			(Some(upload_url), Some(file_id)) => (upload_url, file_id),
+			_ => return Err(SinkError::new("Slack did not return an upload URL")),
+		};
+
+		self.client.post(upload_url)
+			.header("Content-Type", "application/pdf")
+			.body(document.content.clone())
+			.send()
+			.and_then(|response| response.error_for_status())
+			.map_err(error)?;
+
+		self.client.post(self.method_url("files.completeUploadExternal"))
+			.bearer_auth(self.config.token.expose())
+			.json(&json!({
+				"files": [{ "id": file_id, "title": document.filename }],
+				"channel_id": self.config.channel,
+				"initial_comment": render(&self.config.message, &document.template_values()),
+			}))
+			.send()
+			.and_then(|response| response.json::<ApiResponse>())
+			.map_err(error)?
+			.check("files.completeUploadExternal")
+			.map(|_| ())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reports_api_errors() {
+		let response = serde_json::from_str::<ApiResponse>(r#"{"ok": false, "error": "not_in_channel"}"#).unwrap();
+		assert_eq!(response.check("files.completeUploadExternal").unwrap_err().details,
+			"Slack files.completeUploadExternal failed: not_in_channel");
+
+		let response = serde_json::from_str::<ApiResponse>(r#"{"ok": true, "upload_url": "https://files.slack.com/upload/v1/abc", "file_id": "F123"}"#).unwrap();
+		assert_eq!(response.check("files.getUploadURLExternal").unwrap().file_id.as_deref(), Some("F123"));
+	}
+
+	#[test]
+	fn joins_method_urls() {
+		let sink = SlackSink::new(toml::from_str("token = \"xoxb\"\nchannel = \"C0123456789\"\napi_url = \"http://chat.local/api/\"").unwrap());
+		assert_eq!(sink.method_url("files.getUploadURLExternal"), "http://chat.local/api/files.getUploadURLExternal");
+	}
+}
@@ -0,0 +1,115 @@
+use std::time::Duration;
+use reqwest::blocking::multipart::{Form, Part};
+use reqwest::blocking::{Client, ClientBuilder};
+use serde::Deserialize;
+use crate::processing::Document;
+use crate::secret::Secret;
+use crate::sinks::{default_chat_message, Sink, SinkError};
+use crate::template::render;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TelegramConfig {
+	pub bot_token: Secret,
+	pub chat_id: String,
+	/// Template for the caption of the file
+	#[serde(default = "default_chat_message")]
+	pub message: String,
+	#[serde(default = "default_api_url")]
+	pub api_url: String,
+}
+
+fn default_api_url() -> String {
+	"https://api.telegram.org".to_string()
+}
+
+#[derive(Deserialize, Debug)]
+struct BotResponse {
+	ok: bool,
+	description: Option<String>,
+}
+
+impl BotResponse {
+	fn check(self) -> Result<(), SinkError> {
+		match self.ok {
+			true => Ok(()),
+			false => Err(SinkError::new(&format!("Telegram rejected the document: {}", self.description.unwrap_or_default()))),
+		}
+	}
+}
+
+impl TelegramConfig {
+	pub fn name(&self) -> String {
+		format!("Telegram chat {}", self.chat_id)
+	}
+}
+
+pub struct TelegramSink {
+	config: TelegramConfig,
+	client: Client,
+}
+
+impl TelegramSink {
+	pub fn new(config: TelegramConfig) -> TelegramSink {
+		let client = ClientBuilder::new()
+			.timeout(Duration::from_secs(3 * 60))
+			.build()
+			.expect("Error building the Telegram client");
+
+		TelegramSink { config, client }
+	}
+
+	fn method_url(&self, method: &str) -> String {
+		format!("{}/bot{}/{}", self.config.api_url.trim_end_matches('/'), self.config.bot_token.expose(), method)
+	}
+}
+
+impl Sink for TelegramSink {
+	fn name(&self) -> String {
+		self.config.name()
+	}
+
+	fn deliver(&self, document: &Document) -> Result<(), SinkError> {
+		let url = self.method_url("sendDocument");
+
+		let file = Part::bytes(document.content.clone())
+			.file_name(document.filename.clone())
+			.mime_str("application/pdf")
+			.expect("application/pdf is a valid mime type");
+
+		let form = Form::new()
+			.text("chat_id", self.config.chat_id.clone())
+			.text("caption", render(&self.config.message, &document.template_values()))
+			.part("document", file);
+
+		// the URL contains the bot token and must not end up in the logs
+		let response = self.client.post(url)
+			.multipart(form)
+			.send()
+			.and_then(|response| response.json::<BotResponse>())
+			.map_err(|e| SinkError::new(&format!("Error sending to Telegram: {}", e.without_url())))?;
+
+		response.check()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reports_api_errors() {
+		let response = serde_json::from_str::<BotResponse>(r#"{"ok": false, "error_code": 400, "description": "Bad Request: chat not found"}"#).unwrap();
+		assert_eq!(response.check().unwrap_err().details, "Telegram rejected the document: Bad Request: chat not found");
+
+		let response = serde_json::from_str::<BotResponse>(r#"{"ok": true, "result": {"message_id": 42}}"#).unwrap();
+		assert!(response.check().is_ok());
+	}
+
+	#[test]
+	fn joins_method_urls() {
+		let sink = TelegramSink::new(toml::from_str("bot_token = \"123:abc\"\nchat_id = \"-100\"\napi_url = \"http://bots.local/\"").unwrap());
+		assert_eq!(sink.method_url("sendDocument"), "http://bots.local/bot123:abc/sendDocument");
+		let sink = TelegramSink::new(toml::from_str("bot_token = \"123:abc\"\nchat_id = \"-100\"").unwrap());
+		assert_eq!(sink.method_url("sendDocument"), "https://api.telegram.org/bot123:abc/sendDocument");
+	}
+}
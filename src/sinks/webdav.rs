@@ -0,0 +1,118 @@
+use std::time::Duration;
+use reqwest::blocking::{Client, ClientBuilder, RequestBuilder};
+use reqwest::{Method, StatusCode, Url};
+use serde::Deserialize;
+use crate::processing::Document;
+use crate::secret::Secret;
+use crate::sinks::{endpoint, format_date, Sink, SinkError};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebdavConfig {
+	/// Base URL of the upload folder, e.g. `https://cloud.example.com/remote.php/dav/files/scanner/Scans`
+	pub url: String,
+	pub username: Option<String>,
+	/// Password or app password
+	pub password: Option<Secret>,
+	/// chrono format string for date based subfolders, empty to upload into the base folder
+	#[serde(default = "default_subfolder")]
+	pub subfolder: String,
+}
+
+fn default_subfolder() -> String {
+	"%Y/%m".to_string()
+}
+
+impl WebdavConfig {
+	pub fn name(&self) -> String {
+		format!("WebDAV at {}", endpoint(&self.url))
+	}
+}
+
+pub struct WebdavSink {
+	config: WebdavConfig,
+	client: Client,
+}
+
+impl WebdavSink {
+	pub fn new(config: WebdavConfig) -> WebdavSink {
+		let client = ClientBuilder::new()
+			.timeout(Duration::from_secs(3 * 60))
+			.build()
+			.expect("Error building the WebDAV client");
+
+		WebdavSink { config, client }
+	}
+
+	fn request(&self, method: Method, url: Url) -> RequestBuilder {
+		let request = self.client.request(method, url);
+		match &self.config.username {
+			Some(username) => request.basic_auth(username, self.config.password.as_ref().map(Secret::expose)),
+			None => request,
+		}
+	}
+
+	fn url(&self, segments: &[String]) -> Result<Url, SinkError> {
+		let mut url = Url::parse(&self.config.url)
+			.map_err(|e| SinkError::new(&format!("Invalid WebDAV URL {}: {}", self.config.url, e)))?;
+		url.path_segments_mut()
+			.map_err(|_| SinkError::new(&format!("Invalid WebDAV URL {}", self.config.url)))?
+			.pop_if_empty()
+			.extend(segments);
+		Ok(url)
+	}
+
+	/// Creates every folder of the path. Existing folders are answered with
+	/// 405 Method Not Allowed.
+	fn create_folders(&self, folders: &[String]) -> Result<(), SinkError> {
+		let mkcol = Method::from_bytes(b"MKCOL").expect("MKCOL is a valid method");
+
+		for depth in 1..=folders.len() {
+			let mut url = self.url(&folders[..depth])?;
+			url.path_segments_mut()
+				.expect("URL was already checked")
+				.push("");
+
+			let response = self.request(mkcol.clone(), url.clone())
+				.send()
+				.map_err(|e| SinkError::new(&format!("Error creating folder {}: {}", url, e)))?;
+
+			match response.status() {
+				StatusCode::CREATED => log::debug!("Created WebDAV folder {}", url),
+				StatusCode::METHOD_NOT_ALLOWED => {}
+				status => return Err(SinkError::new(&format!("Error creating folder {}: status {}", url, status))),
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Sink for WebdavSink {
+	fn name(&self) -> String {
+		self.config.name()
+	}
+
+	fn deliver(&self, document: &Document) -> Result<(), SinkError> {
+		let folders = format_date(&document.created, &self.config.subfolder)?
+			.split('/')
+			.filter(|folder| !folder.is_empty())
+			.map(|folder| folder.to_string())
+			.collect::<Vec<String>>();
+
+		self.create_folders(&folders)?;
+
+		let mut path = folders;
+		path.push(document.filename.clone());
+		let url = self.url(&path)?;
+
+		let response = self.request(Method::PUT, url.clone())
+			.header("Content-Type", "application/pdf")
+			.body(document.content.clone())
+			.send()
+			.map_err(|e| SinkError::new(&format!("Error uploading to {}: {}", url, e)))?;
+
+		match response.status().is_success() {
+			true => Ok(()),
+			false => Err(SinkError::new(&format!("Error uploading to {}: status {}", url, response.status()))),
+		}
+	}
+}
@@ -0,0 +1,252 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hmac::{Hmac, Mac};
+use reqwest::blocking::{Client, ClientBuilder};
+use serde::Deserialize;
+use sha2::Sha256;
+use crate::processing::Document;
+use crate::secret::Secret;
+use crate::sinks::{endpoint, Sink, SinkError};
+use crate::template::render;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+	/// multipart/form-data with the document in the `document` part
+	#[default]
+	Multipart,
+	/// JSON object with the base64 encoded document in `content`
+	Json,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebhookConfig {
+	pub url: String,
+	#[serde(default)]
+	pub format: WebhookFormat,
+	/// Extra fields sent along with the document, values are templates
+	#[serde(default = "default_fields")]
+	pub fields: HashMap<String, String>,
+	/// Key for the HMAC-SHA256 signature of the request body
+	pub secret: Option<Secret>,
+	#[serde(default = "default_signature_header")]
+	pub signature_header: String,
+	/// Number of retries after 5xx responses or connection errors
+	#[serde(default = "default_retries")]
+	pub retries: u32,
+	/// Delay before the first retry, doubled for every further retry
+	#[serde(default = "default_retry_delay")]
+	pub retry_delay_secs: u64,
+}
+
+fn default_fields() -> HashMap<String, String> {
+	HashMap::from([
+		("destination".to_string(), "{{destination}}".to_string()),
+		("timestamp".to_string(), "{{timestamp}}".to_string()),
+		("pages".to_string(), "{{pages}}".to_string()),
+		("printer_serial".to_string(), "{{printer_serial}}".to_string()),
+	])
+}
+
+fn default_signature_header() -> String {
+	"X-Signature-256".to_string()
+}
+
+fn default_retries() -> u32 {
+	3
+}
+
+fn default_retry_delay() -> u64 {
+	5
+}
+
+impl WebhookConfig {
+	pub fn name(&self) -> String {
+		format!("Webhook {}", endpoint(&self.url))
+	}
+}
+
+pub struct WebhookSink {
+	config: WebhookConfig,
+	client: Client,
+}
+
+impl WebhookSink {
+	pub fn new(config: WebhookConfig) -> WebhookSink {
+		let client = ClientBuilder::new()
+			.timeout(Duration::from_secs(3 * 60))
+			.build()
+			.expect("Error building the webhook client");
+
+		WebhookSink { config, client }
+	}
+
+	fn fields(&self, document: &Document) -> BTreeMap<String, String> {
+		let values = document.template_values();
+		self.config.fields.iter()
+			.map(|(name, template)| (name.clone(), render(template, &values)))
+			.collect()
+	}
+
+	/// Returns the content type and the body of the request.
+	fn body(&self, document: &Document) -> (String, Vec<u8>) {
+		let fields = self.fields(document);
+
+		match self.config.format {
+			WebhookFormat::Json => {
+				let mut object = serde_json::Map::new();
+				for (name, value) in fields {
+					object.insert(name, serde_json::Value::String(value));
+				}
+				object.insert("filename".to_string(), document.filename.clone().into());
+				object.insert("content_type".to_string(), "application/pdf".into());
+				object.insert("content".to_string(), STANDARD.encode(&document.content).into());
+				("application/json".to_string(), serde_json::Value::Object(object).to_string().into_bytes())
+			}
+			WebhookFormat::Multipart => {
+				let boundary = boundary(document);
+				let mut body = Vec::new();
+				for (name, value) in fields {
+					body.extend_from_slice(format!(
+						"--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+						boundary, name.replace('"', "%22"), value).as_bytes());
+				}
+				body.extend_from_slice(format!(
+					"--{}\r\nContent-Disposition: form-data; name=\"document\"; filename=\"{}\"\r\nContent-Type: application/pdf\r\n\r\n",
+					boundary, document.filename.replace('"', "%22")).as_bytes());
+				body.extend_from_slice(&document.content);
+				body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+				(format!("multipart/form-data; boundary={}", boundary), body)
+			}
+		}
+	}
+}
+
+/// The multipart body is built by hand because the signature has to cover
+/// the exact bytes that are sent.
+fn boundary(document: &Document) -> String {
+	let mut hasher = DefaultHasher::new();
+	document.filename.hash(&mut hasher);
+	document.content.len().hash(&mut hasher);
+	SystemTime::now().hash(&mut hasher);
+	format!("rust-hp-{:016x}", hasher.finish())
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+	let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+		.expect("HMAC accepts keys of any length");
+	mac.update(body);
+	format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+impl Sink for WebhookSink {
+	fn name(&self) -> String {
+		self.config.name()
+	}
+
+	fn deliver(&self, document: &Document) -> Result<(), SinkError> {
+		let (content_type, body) = self.body(document);
+		let signature = self.config.secret.as_ref()
+			.map(|secret| signature(secret.expose(), &body));
+
+		let mut delay = Duration::from_secs(self.config.retry_delay_secs);
+		let mut attempt = 0;
+		loop {
+			let mut request = self.client.post(&self.config.url)
+				.header("Content-Type", &content_type)
+				.body(body.clone());
+			if let Some(signature) = &signature {
+				request = request.header(&self.config.signature_header, signature);
+			}
+
+			let error = match request.send() {
+				Ok(response) if response.status().is_success() => return Ok(()),
+				Ok(response) if response.status().is_server_error() => format!("status {}", response.status()),
+				Ok(response) => return Err(SinkError::new(&format!("Webhook returned status {}", response.status()))),
+				// the URL of the error may contain the token of the webhook
+				Err(e) => e.without_url().to_string(),
+			};
+
+			if attempt >= self.config.retries {
+				return Err(SinkError::new(&format!("Webhook failed after {} attempts: {}", attempt + 1, error)));
+			}
+
+			attempt += 1;
+			log::warn!("Webhook request failed with {}, retrying in {} seconds", error, delay.as_secs());
+			thread::sleep(delay);
+			delay *= 2;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::Local;
+	use super::*;
+
+	fn document() -> Document {
+		Document {
+			filename: "Scan \"1\".pdf".to_string(),
+			content: b"%PDF-1.4\n%%EOF".to_vec(),
+			page_count: 2,
+			destination: "Office".to_string(),
+			printer: "printer".to_string(),
+			printer_model: "HP OfficeJet".to_string(),
+			printer_serial: "CN123".to_string(),
+			created: Local::now(),
+			scan_id: None,
+			thumbnail: None,
+		}
+	}
+
+	fn sink(format: &str) -> WebhookSink {
+		WebhookSink::new(toml::from_str(&format!(
+			"url = \"https://example.com/hook\"\nformat = \"{}\"\n[fields]\npages = \"{{{{pages}}}}\"\ndestination = \"{{{{destination}}}}\"",
+			format)).unwrap())
+	}
+
+	#[test]
+	fn signs_known_vectors() {
+		// RFC 4231 test case 2
+		assert_eq!(signature("Jefe", b"what do ya want for nothing?"),
+			"sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+		// example of the GitHub webhook documentation
+		assert_eq!(signature("It's a Secret to Everybody", b"Hello, World!"),
+			"sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17");
+	}
+
+	#[test]
+	fn builds_multipart_body() {
+		let (content_type, body) = sink("multipart").body(&document());
+		let boundary = content_type.strip_prefix("multipart/form-data; boundary=").unwrap();
+		assert!(boundary.starts_with("rust-hp-"));
+
+		let expected = [
+			format!("--{}\r\nContent-Disposition: form-data; name=\"destination\"\r\n\r\nOffice\r\n", boundary),
+			format!("--{}\r\nContent-Disposition: form-data; name=\"pages\"\r\n\r\n2\r\n", boundary),
+			format!("--{}\r\nContent-Disposition: form-data; name=\"document\"; filename=\"Scan %221%22.pdf\"\r\nContent-Type: application/pdf\r\n\r\n", boundary),
+			"%PDF-1.4\n%%EOF".to_string(),
+			format!("\r\n--{}--\r\n", boundary),
+		].concat();
+		assert_eq!(String::from_utf8(body).unwrap(), expected);
+	}
+
+	#[test]
+	fn builds_json_body() {
+		let (content_type, body) = sink("json").body(&document());
+		assert_eq!(content_type, "application/json");
+		let json = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+		assert_eq!(json, serde_json::json!({
+			"destination": "Office",
+			"pages": "2",
+			"filename": "Scan \"1\".pdf",
+			"content_type": "application/pdf",
+			"content": STANDARD.encode(b"%PDF-1.4\n%%EOF"),
+		}));
+	}
+}
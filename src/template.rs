@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+/// Replaces `{{ name }}` placeholders with the given values. Unknown
+/// placeholders are replaced with an empty string.
+pub fn render(template: &str, values: &HashMap<&str, String>) -> String {
+	let mut rendered = String::with_capacity(template.len());
+	let mut rest = template;
+
+	while let Some(start) = rest.find("{{") {
+		rendered.push_str(&rest[..start]);
+		match rest[start..].find("}}") {
+			Some(end) => {
+				let name = rest[start + 2..start + end].trim();
+				if let Some(value) = values.get(name) {
+					rendered.push_str(value);
+				}
+				rest = &rest[start + end + 2..];
+			}
+			None => {
+				rendered.push_str(&rest[start..]);
+				rest = "";
+			}
+		}
+	}
+
+	rendered.push_str(rest);
+	rendered
+}
+
+/// Escapes a value for use in HTML templates.
+pub fn escape_html(value: &str) -> String {
+	value.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+		.replace('\'', "&#39;")
+}
+
+/// Formats a size in bytes for humans, for example `1.4 MB`.
+pub fn format_size(bytes: u64) -> String {
+	match bytes {
+		0..=1023 => format!("{} B", bytes),
+		1024..=1048575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+		_ => format!("{:.1} MB", bytes as f64 / 1048576.0),
+	}
+}
+
+/// Makes a value safe to use as part of a file name.
+pub fn sanitize_filename(value: &str) -> String {
+	value.chars()
+		.map(|c| match c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+			true => c,
+			false => '_',
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn renders_placeholders() {
+		let values = HashMap::from([("filename", "Scan.pdf".to_string()), ("pages", "3".to_string())]);
+		assert_eq!(render("{{filename}} with {{ pages }} pages", &values), "Scan.pdf with 3 pages");
+		assert_eq!(render("{{unknown}}|{{pages}}", &values), "|3");
+		assert_eq!(render("no placeholders", &values), "no placeholders");
+		assert_eq!(render("open {{pages", &values), "open {{pages");
+		assert_eq!(render("}} {{pages}}{{pages}}", &values), "}} 33");
+	}
+
+	#[test]
+	fn escapes_html() {
+		assert_eq!(escape_html("<a href=\"x\">Tom & Jerry's</a>"), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;");
+	}
+
+	#[test]
+	fn formats_sizes() {
+		assert_eq!(format_size(512), "512 B");
+		assert_eq!(format_size(1536), "1.5 KB");
+		assert_eq!(format_size(3 * 1048576), "3.0 MB");
+	}
+
+	#[test]
+	fn sanitizes_filenames() {
+		assert_eq!(sanitize_filename("../Invoice 2024/01.pdf"), ".._Invoice_2024_01.pdf");
+	}
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::{env, fs};
+use serde::Deserialize;
+use crate::config::{ConfigError, DestinationConfig};
+use crate::sinks::SinkConfig;
+use crate::sinks::sendgrid::SendgridConfig;
+
+/// Registers a walk-up destination per person in a user directory, so
+/// everyone can pick their own name on the printer panel.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct UsersConfig {
+	/// User directory, falls back to the `USERS_FILE` environment variable
+	pub file: Option<String>,
+	/// Destination whose processing settings and fallback sinks are copied
+	pub template: Option<String>,
+	/// Sinks users can choose from by name. The address of the user is
+	/// filled in as recipient of mail sinks.
+	#[serde(default)]
+	pub sink: HashMap<String, SinkConfig>,
+	/// Used for users without a preferred sink, a SendGrid mail by default
+	pub default_sink: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct UserDirectory {
+	#[serde(rename = "user", default)]
+	users: Vec<User>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct User {
+	/// Shown on the printer panel
+	pub name: String,
+	pub email: String,
+	/// Name of one of the sinks in `[users.sink]`
+	pub sink: Option<String>,
+}
+
+impl UsersConfig {
+	/// Reads the user directory and returns one destination per user.
+	pub fn destinations(&self, configured: &[DestinationConfig]) -> Result<Vec<DestinationConfig>, ConfigError> {
+		let path = match self.file.clone().or_else(|| env::var("USERS_FILE").ok()) {
+			Some(path) => path,
+			None => return Ok(Vec::new()),
+		};
+
+		log::info!("Reading user directory from {}", path);
+		let content = fs::read_to_string(&path)
+			.map_err(|e| ConfigError::new(&format!("Error reading {}: {}", path, e)))?;
+		let directory = toml::from_str::<UserDirectory>(&content)
+			.map_err(|e| ConfigError::new(&format!("Error parsing {}: {}", path, e)))?;
+
+		let template = match &self.template {
+			Some(name) => Some(configured.iter()
+				.find(|destination| &destination.name == name)
+				.ok_or_else(|| ConfigError::new(&format!("Template destination {} does not exist", name)))?),
+			None => None,
+		};
+
+		for (i, user) in directory.users.iter().enumerate() {
+			if directory.users[..i].iter().any(|other| other.name == user.name) {
+				return Err(ConfigError::new(&format!("User {} is listed twice in {}", user.name, path)));
+			}
+			if configured.iter().any(|destination| destination.name == user.name) {
+				return Err(ConfigError::new(&format!("User {} has the name of a configured destination", user.name)));
+			}
+		}
+
+		directory.users.iter()
+			.map(|user| self.destination(user, template))
+			.collect()
+	}
+
+	fn destination(&self, user: &User, template: Option<&DestinationConfig>) -> Result<DestinationConfig, ConfigError> {
+		let sink = match user.sink.as_ref().or(self.default_sink.as_ref()) {
+			Some(name) => self.sink.get(name)
+				.ok_or_else(|| ConfigError::new(&format!("Sink {} of user {} does not exist", name, user.name)))?
+				.clone(),
+			None => SinkConfig::Sendgrid(SendgridConfig::default()),
+		};
+
+		Ok(DestinationConfig {
+			name: user.name.clone(),
+			filename: template.and_then(|template| template.filename.clone()),
+			deskew: template.and_then(|template| template.deskew.clone()),
+			split: template.and_then(|template| template.split.clone()),
+			ocr: template.and_then(|template| template.ocr.clone()),
+			pdf: template.and_then(|template| template.pdf.clone()),
+			compression: template.and_then(|template| template.compression.clone()),
+			sink: vec![sink.with_recipient(&user.email)],
+			fallback_sink: template.map(|template| template.fallback_sink.clone()).unwrap_or_default(),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::process;
+	use super::*;
+
+	const USERS: &str = "[[user]]\nname = \"Alice\"\nemail = \"alice@example.com\"\nsink = \"local\"\n\n[[user]]\nname = \"Bob\"\nemail = \"bob@example.com\"\n";
+
+	/// Users config reading the given directory, written to a file named after `name`.
+	fn config(name: &str, users: &str, extra: &str) -> UsersConfig {
+		let path = env::temp_dir().join(format!("rust-hp-users-{}-{}.toml", name, process::id()));
+		fs::write(&path, users).unwrap();
+		let mut config = toml::from_str::<UsersConfig>(&format!(
+			"template = \"Office\"\n{}\n[sink.local]\ntype = \"sendmail\"\nfrom = \"scanner@example.com\"", extra)).unwrap();
+		config.file = Some(path.display().to_string());
+		config
+	}
+
+	fn read(config: &UsersConfig, configured: &[DestinationConfig]) -> Result<Vec<DestinationConfig>, ConfigError> {
+		let result = config.destinations(configured);
+		fs::remove_file(config.file.as_ref().unwrap()).unwrap();
+		result
+	}
+
+	fn office() -> DestinationConfig {
+		toml::from_str("name = \"Office\"\nfilename = \"{{name}}.pdf\"\n[[fallback_sink]]\ntype = \"sendmail\"").unwrap()
+	}
+
+	fn recipients(sink: &SinkConfig) -> &[String] {
+		match sink {
+			SinkConfig::Sendmail(sendmail) => &sendmail.mail.to,
+			SinkConfig::Sendgrid(sendgrid) => &sendgrid.mail.to,
+			other => panic!("unexpected sink {}", other.name()),
+		}
+	}
+
+	#[test]
+	fn maps_users_to_destinations() {
+		let destinations = read(&config("map", USERS, ""), &[office()]).unwrap();
+		assert_eq!(destinations.len(), 2);
+
+		let alice = &destinations[0];
+		assert_eq!(alice.name, "Alice");
+		assert!(matches!(alice.sink[..], [SinkConfig::Sendmail(_)]));
+		assert_eq!(recipients(&alice.sink[0]), ["alice@example.com"]);
+		assert_eq!(alice.filename.as_deref(), Some("{{name}}.pdf"));
+		assert_eq!(alice.fallback_sink.len(), 1);
+
+		// without a preferred or default sink users get a SendGrid mail
+		let bob = &destinations[1];
+		assert!(matches!(bob.sink[..], [SinkConfig::Sendgrid(_)]));
+		assert_eq!(recipients(&bob.sink[0]), ["bob@example.com"]);
+	}
+
+	#[test]
+	fn uses_default_sink() {
+		let destinations = read(&config("default", USERS, "default_sink = \"local\""), &[office()]).unwrap();
+		assert!(matches!(destinations[1].sink[..], [SinkConfig::Sendmail(_)]));
+		assert_eq!(recipients(&destinations[1].sink[0]), ["bob@example.com"]);
+	}
+
+	#[test]
+	fn rejects_unknown_sinks() {
+		let users = "[[user]]\nname = \"Carol\"\nemail = \"carol@example.com\"\nsink = \"fax\"";
+		let error = read(&config("unknown", users, ""), &[office()]).unwrap_err();
+		assert_eq!(error.details, "Sink fax of user Carol does not exist");
+	}
+
+	#[test]
+	fn rejects_duplicate_names() {
+		let users = format!("{}\n[[user]]\nname = \"Alice\"\nemail = \"alice@example.org\"", USERS);
+		let error = read(&config("twice", &users, ""), &[office()]).unwrap_err();
+		assert!(error.details.starts_with("User Alice is listed twice"), "{}", error);
+
+		let error = read(&config("destination", "[[user]]\nname = \"Office\"\nemail = \"office@example.com\"", ""), &[office()]).unwrap_err();
+		assert_eq!(error.details, "User Office has the name of a configured destination");
+	}
+
+	#[test]
+	fn rejects_unknown_template() {
+		let error = read(&config("template", USERS, ""), &[]).unwrap_err();
+		assert_eq!(error.details, "Template destination Office does not exist");
+	}
+
+	#[test]
+	fn reports_unreadable_files() {
+		let config = UsersConfig { file: Some("/nonexistent/users.toml".to_string()), ..UsersConfig::default() };
+		assert!(config.destinations(&[]).unwrap_err().details.starts_with("Error reading /nonexistent/users.toml"));
+	}
+}
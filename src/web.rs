@@ -0,0 +1,125 @@
+use crate::history::ScanRecord;
+use crate::objects::{ApiError, ScanStatus};
+use crate::template::{escape_html, format_size};
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 70em; padding: 0 1em; color: #222; }
+table { border-collapse: collapse; width: 100%; }
+th, td { text-align: left; padding: .4em .6em; border-bottom: 1px solid #ddd; vertical-align: top; }
+img { max-width: 120px; border: 1px solid #ccc; }
+.ok { color: #17803d; }
+.failed { color: #c0262d; }
+.muted { color: #777; }
+form { display: inline; }
+";
+
+/// A configured destination and its id on the printer if registered.
+pub struct DestinationStatus {
+	pub name: String,
+	pub id: Option<String>,
+}
+
+/// A configured printer, its scanner state and destinations.
+pub struct PrinterStatus {
+	pub name: String,
+	pub host: String,
+	pub status: Result<ScanStatus, ApiError>,
+	pub destinations: Vec<DestinationStatus>,
+}
+
+/// Status page listing the printers, their destinations and recent scans.
+pub fn dashboard(printers: &[PrinterStatus], scans: &[ScanRecord], archive: bool) -> String {
+	let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta http-equiv=\"refresh\" content=\"30\">\n<title>HP Scan to</title>\n<style>{}</style>\n</head>\n<body>\n", STYLE);
+
+	html.push_str("<h1>HP Scan to</h1>\n");
+	for printer in printers {
+		html.push_str(&printer_section(printer));
+	}
+
+	html.push_str("<h2>Recent scans</h2>\n");
+	if scans.is_empty() {
+		html.push_str("<p class=\"muted\">No scans yet</p>\n");
+	}
+	else {
+		html.push_str("<table>\n<tr><th></th><th>Started</th><th>Destination</th><th>Status</th><th>Documents</th><th>Deliveries</th><th></th></tr>\n");
+		for scan in scans {
+			html.push_str(&scan_row(scan, archive));
+		}
+		html.push_str("</table>\n");
+	}
+
+	html.push_str("</body>\n</html>\n");
+	html
+}
+
+fn printer_section(printer: &PrinterStatus) -> String {
+	let mut html = format!("<h2>{} <span class=\"muted\">{}</span></h2>\n", escape_html(&printer.name), escape_html(&printer.host));
+	match &printer.status {
+		Ok(status) => html.push_str(&format!("<p class=\"ok\">{}, document feeder {}</p>\n",
+			escape_html(&status.scanner_status), escape_html(&status.adf_state))),
+		Err(e) => html.push_str(&format!("<p class=\"failed\">not reachable ({})</p>\n",
+			escape_html(&e.to_string()))),
+	}
+
+	html.push_str("<table>\n<tr><th>Destination</th><th>Status</th></tr>\n");
+	for destination in &printer.destinations {
+		let status = match &destination.id {
+			Some(id) => format!("<span class=\"ok\">registered</span> <span class=\"muted\">{}</span>", escape_html(id)),
+			None => "<span class=\"failed\">not registered</span>".to_string(),
+		};
+		html.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>\n", escape_html(&destination.name), status));
+	}
+	html.push_str("</table>\n");
+	html
+}
+
+fn scan_row(scan: &ScanRecord, archive: bool) -> String {
+	let thumbnail = scan.documents.iter()
+		.find(|document| document.thumbnail)
+		.map(|document| format!("<img src=\"/documents/{}/thumbnail.jpg\" alt=\"\">", document.id))
+		.unwrap_or_default();
+
+	let status = match &scan.error {
+		Some(error) => format!("<span class=\"failed\">{}</span><br>{}", escape_html(&scan.status), escape_html(error)),
+		None => format!("<span class=\"{}\">{}</span>", if scan.status == "completed" { "ok" } else { "muted" }, escape_html(&scan.status)),
+	};
+
+	let documents = match scan.documents.is_empty() {
+		true => format!("{} pages, {}", scan.pages, format_size(scan.size as u64)),
+		false => scan.documents.iter()
+			.map(|document| format!("<a href=\"/documents/{}\">{}</a> <span class=\"muted\">{} pages, {}</span>",
+				document.id, escape_html(&document.filename), document.pages, format_size(document.size as u64)))
+			.collect::<Vec<String>>()
+			.join("<br>"),
+	};
+
+	// the latest attempt per document and sink counts
+	let mut deliveries: Vec<&crate::history::DeliveryRecord> = Vec::new();
+	for delivery in &scan.deliveries {
+		match deliveries.iter_mut().find(|other| other.filename == delivery.filename && other.sink == delivery.sink) {
+			Some(other) => *other = delivery,
+			None => deliveries.push(delivery),
+		}
+	}
+	let deliveries = deliveries.iter()
+		.map(|delivery| match &delivery.error {
+			None => format!("<span class=\"ok\">&#10003;</span> {}", escape_html(&delivery.sink)),
+			Some(error) => format!("<span class=\"failed\" title=\"{}\">&#10007;</span> {}", escape_html(error), escape_html(&delivery.sink)),
+		})
+		.collect::<Vec<String>>()
+		.join("<br>");
+
+	let resend = match archive && !scan.documents.is_empty() {
+		true => format!("<form method=\"post\" action=\"/scans/{}/resend\"><button>Send again</button></form>", scan.id),
+		false => String::new(),
+	};
+
+	format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+		thumbnail,
+		scan.started.format("%Y-%m-%d %H:%M:%S"),
+		escape_html(&scan.destination),
+		status,
+		documents,
+		deliveries,
+		resend)
+}